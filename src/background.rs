@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::threads::Lifecycle;
+
+/* Detached background threads that are still accounted for.
+
+`thread::spawn` without keeping the join handle makes a thread impossible to
+wait for. `background::spawn` also detaches the thread from the caller, but
+records it in a global registry. At exit, `background::shutdown(timeout)` joins
+every registered thread that finishes within the timeout and reports the ones
+that did not as leaked. */
+
+struct Task {
+  name: String,
+  handle: JoinHandle<()>,
+  done: Arc<AtomicBool>,
+}
+
+struct Registry {
+  tasks: Mutex<Vec<Task>>,
+  // Notified whenever a background thread finishes.
+  finished: Condvar,
+}
+
+static REGISTRY: Registry = Registry { tasks: Mutex::new(Vec::new()), finished: Condvar::new() };
+
+// Marks the task as done when the thread finishes, also when it panics.
+
+struct MarkDone(Arc<AtomicBool>);
+
+impl Drop for MarkDone {
+  fn drop(&mut self) {
+    let _tasks = REGISTRY.tasks.lock().unwrap_or_else(|e| e.into_inner());
+    self.0.store(true, Ordering::SeqCst);
+    REGISTRY.finished.notify_all();
+  }
+}
+
+// What `shutdown` did with the registered threads.
+
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+  // Threads that finished normally and were joined.
+  pub joined: Vec<String>,
+  // Threads that finished by panicking and were joined.
+  pub panicked: Vec<String>,
+  // Threads still running at the timeout. They stay registered, so a later
+  // `shutdown` can try again.
+  pub leaked: Vec<String>,
+}
+
+impl ShutdownReport {
+  pub fn is_clean(&self) -> bool {
+    self.panicked.is_empty() && self.leaked.is_empty()
+  }
+}
+
+// Spawns a named, detached thread that is tracked in the global registry.
+
+pub fn spawn<F>(name: impl Into<String>, f: F)
+where
+  F: FnOnce() + Send + 'static,
+{
+  let name = name.into();
+  let done = Arc::new(AtomicBool::new(false));
+  let marker = MarkDone(done.clone());
+  let thread_name = name.clone();
+  let handle = thread::Builder::new()
+    .name(name.clone())
+    .spawn(move || {
+      let _marker = marker;
+      // Dropped before the marker, so `Finished` is emitted before the thread
+      // counts as done.
+      let _lifecycle = Lifecycle::start(thread_name);
+      f()
+    })
+    .expect("failed to spawn background thread");
+  REGISTRY.tasks.lock().unwrap().push(Task { name, handle, done });
+}
+
+// The names of the registered threads that have not been joined yet.
+
+pub fn running() -> Vec<String> {
+  REGISTRY.tasks.lock().unwrap().iter().map(|t| t.name.clone()).collect()
+}
+
+// Waits up to `timeout` for all registered threads to finish and joins them.
+
+pub fn shutdown(timeout: Duration) -> ShutdownReport {
+  let deadline = Instant::now() + timeout;
+  let mut tasks = REGISTRY.tasks.lock().unwrap();
+  loop {
+    let now = Instant::now();
+    if now >= deadline || tasks.iter().all(|t| t.done.load(Ordering::SeqCst)) {
+      break;
+    }
+    tasks = REGISTRY.finished.wait_timeout(tasks, deadline - now).unwrap().0;
+  }
+
+  let (done, pending): (Vec<Task>, Vec<Task>) =
+    tasks.drain(..).partition(|t| t.done.load(Ordering::SeqCst));
+  *tasks = pending;
+  let mut report = ShutdownReport {
+    leaked: tasks.iter().map(|t| t.name.clone()).collect(),
+    ..ShutdownReport::default()
+  };
+  drop(tasks);
+
+  // `done` is set just before the thread exits, so these joins return quickly.
+  for task in done {
+    match task.handle.join() {
+      Ok(()) => report.joined.push(task.name),
+      Err(_) => report.panicked.push(task.name),
+    }
+  }
+  report
+}
+
+// The registry is global, so everything is tested in one test function.
+
+#[test]
+fn test_background_shutdown() {
+  let stop = Arc::new(AtomicBool::new(false));
+
+  spawn("quick", || thread::sleep(Duration::from_millis(10)));
+  spawn("failing", || panic!("background failure"));
+  let s = stop.clone();
+  spawn("stuck", move || {
+    while !s.load(Ordering::SeqCst) {
+      thread::sleep(Duration::from_millis(5));
+    }
+  });
+  assert_eq!(running().len(), 3);
+
+  let report = shutdown(Duration::from_millis(200));
+  assert_eq!(report.joined, vec!["quick".to_string()]);
+  assert_eq!(report.panicked, vec!["failing".to_string()]);
+  assert_eq!(report.leaked, vec!["stuck".to_string()]);
+  assert!(!report.is_clean());
+  assert_eq!(running(), vec!["stuck".to_string()]);
+
+  stop.store(true, Ordering::SeqCst);
+  let report = shutdown(Duration::from_secs(5));
+  assert_eq!(report.joined, vec!["stuck".to_string()]);
+  assert!(report.is_clean());
+  assert!(running().is_empty());
+}
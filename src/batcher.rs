@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::errors::{MultiSendError, TryRecvError};
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::select::Select;
+use crate::threads;
+
+/* Batching messages by key.
+
+A `ByKey` stage reads `(key, item)` pairs from a multi-shot channel, collects
+the items of each key, and sends `(key, batch)` downstream when a batch is
+full or when its time window has passed. The window of a key starts with the
+first item of its batch, so an item waits at most `window` before it is sent,
+however slowly the other items of its key arrive.
+
+The stage runs on its own thread, and waits for input with a `Select` so that
+it also wakes up when the next window ends. When the input is closed, it sends
+the batches that are left, oldest first, and closes the output. It stops early
+if the output receiver is dropped. */
+
+#[derive(Clone, Copy, Debug)]
+pub struct ByKey {
+  max_size: usize,
+  window: Duration,
+}
+
+struct Batch<T> {
+  deadline: Instant,
+  items: Vec<T>,
+}
+
+impl ByKey {
+  // A stage that sends batches of at most `max_size` items, at the latest
+  // `window` after their first item arrived.
+
+  pub fn new(max_size: usize, window: Duration) -> ByKey {
+    assert!(max_size > 0, "ByKey: max_size must be positive");
+    ByKey { max_size, window }
+  }
+
+  // Starts the stage on a new thread. The thread ends once the input is closed
+  // and all batches have been sent.
+
+  pub fn spawn<K, T>(self, input: MultiRecv<(K, T)>) -> (MultiRecv<(K, Vec<T>)>, JoinHandle<()>)
+  where
+    K: Hash + Eq + Clone + Send + 'static,
+    T: Send + 'static,
+  {
+    let (output, batches) = new_multi_chan();
+    let handle = threads::spawn("batcher".to_string(), move || {
+      // An error only means that nobody is listening anymore.
+      let _ = self.run(input, output);
+    });
+    (batches, handle)
+  }
+
+  fn run<K: Hash + Eq + Clone, T>(
+    self,
+    mut input: MultiRecv<(K, T)>,
+    mut output: MultiSend<(K, Vec<T>)>,
+  ) -> Result<(), MultiSendError<(K, Vec<T>)>> {
+    let mut batches: HashMap<K, Batch<T>> = HashMap::new();
+    loop {
+      let now = Instant::now();
+      for (key, batch) in take_batches(&mut batches, |b| b.deadline <= now) {
+        output = output.send((key, batch))?;
+      }
+
+      let next_deadline = batches.values().map(|b| b.deadline).min();
+      let mut select = Select::new();
+      select.add(&input);
+      match next_deadline {
+        Some(deadline) => {
+          select.ready_timeout(deadline.saturating_duration_since(now));
+        }
+        None => {
+          select.ready();
+        }
+      }
+
+      match input.try_recv() {
+        Ok((key, item)) => {
+          let batch = batches.entry(key.clone()).or_insert_with(|| {
+            Batch { deadline: Instant::now() + self.window, items: Vec::new() }
+          });
+          batch.items.push(item);
+          if batch.items.len() >= self.max_size {
+            let batch = batches.remove(&key).unwrap();
+            output = output.send((key, batch.items))?;
+          }
+        }
+        // The window of some batch has ended.
+        Err(TryRecvError::Empty) => {}
+        Err(TryRecvError::Disconnected) => {
+          for (key, batch) in take_batches(&mut batches, |_| true) {
+            output = output.send((key, batch))?;
+          }
+          return Ok(());
+        }
+      }
+    }
+  }
+}
+
+// Removes the batches for which `pred` holds, oldest first.
+
+fn take_batches<K: Hash + Eq + Clone, T>(
+  batches: &mut HashMap<K, Batch<T>>,
+  pred: impl Fn(&Batch<T>) -> bool,
+) -> Vec<(K, Vec<T>)> {
+  let mut keys: Vec<_> = batches.iter().filter(|(_, b)| pred(b)).map(|(k, b)| (b.deadline, k.clone())).collect();
+  keys.sort_by_key(|(deadline, _)| *deadline);
+  keys.into_iter().map(|(_, k)| {
+    let batch = batches.remove(&k).unwrap();
+    (k, batch.items)
+  }).collect()
+}
+
+#[test]
+fn test_by_key_size_and_close() {
+  let (mut s, r) = new_multi_chan();
+  for (k, v) in [("a", 1), ("b", 2), ("a", 3), ("a", 4), ("b", 5)] {
+    s = s.send((k, v)).unwrap();
+  }
+  drop(s);
+  let (batches, h) = ByKey::new(3, Duration::from_secs(10)).spawn(r);
+  let batches: Vec<_> = batches.into_iter().collect();
+  h.join().unwrap();
+  // "a" is full after three items; "b" is sent when the input closes.
+  assert_eq!(batches, vec![("a", vec![1, 3, 4]), ("b", vec![2, 5])]);
+}
+
+#[test]
+fn test_by_key_window() {
+  let (s, r) = new_multi_chan();
+  let (mut batches, h) = ByKey::new(100, Duration::from_millis(30)).spawn(r);
+  let start = Instant::now();
+  let s = s.send((1, "x")).unwrap().send((1, "y")).unwrap();
+
+  // The batch is sent when its window ends, while the input is still open.
+  let (batch, next) = batches.recv().unwrap();
+  assert_eq!(batch, (1, vec!["x", "y"]));
+  assert!(start.elapsed() >= Duration::from_millis(30));
+  batches = next;
+
+  s.close();
+  assert!(batches.recv().is_none());
+  h.join().unwrap();
+}
@@ -0,0 +1,289 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::errors::{SendError, TryRecvError};
+use crate::mpmc;
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::threads;
+
+/* Broadcast channels.
+
+Every message sent on a broadcast channel is delivered to every receiver that
+is subscribed at that time, e.g. to tell all threads of a pool to reload their
+configuration or to shut down. New receivers can be subscribed at any time with
+`subscribe()`; they only see the messages sent after that.
+
+Each receiver has its own multi-shot channel, and `send` sends a clone of the
+message on each of them. The channels are unbounded, so a slow receiver does
+not hold up the sender or the other receivers. The sender can be cloned; the
+stream ends for all receivers once every clone is gone.
+
+The messages are numbered in the order they are sent, starting from 1, and
+every receiver keeps track of how far it has read. `wait_consumed(seq)` blocks
+until every receiver has read all messages up to `seq`, e.g. before a producer
+reuses a buffer it shared with them, or to keep them in lock-step. A receiver
+that subscribed later counts as having read what was sent before it, and one
+that was dropped is not waited for. */
+
+pub struct BroadcastSend<T> {
+  subscribers: Arc<Mutex<Subscribers<T>>>,
+}
+
+struct Subscribers<T> {
+  senders: Vec<(MultiSend<T>, Arc<Cursor>)>,
+  // The number of the last message sent.
+  seq: u64,
+}
+
+pub struct BroadcastRecv<T> {
+  // `None` once the stream has ended.
+  receiver: Option<MultiRecv<T>>,
+  cursor: Arc<Cursor>,
+}
+
+// How far a receiver has read: the number of the last message it received,
+// or `None` once it has been dropped.
+
+struct Cursor {
+  consumed: Mutex<Option<u64>>,
+  cond: Condvar,
+}
+
+impl Cursor {
+  fn advance(&self) {
+    if let Some(n) = self.consumed.lock().unwrap().as_mut() {
+      *n += 1;
+    }
+    self.cond.notify_all();
+  }
+
+  fn wait_for(&self, seq: u64) {
+    let consumed = self.consumed.lock().unwrap();
+    drop(self.cond.wait_while(consumed, |c| c.is_some_and(|n| n < seq)).unwrap());
+  }
+}
+
+pub fn channel<T: Clone>() -> (BroadcastSend<T>, BroadcastRecv<T>) {
+  let subscribers = Subscribers { senders: Vec::new(), seq: 0 };
+  let sender = BroadcastSend { subscribers: Arc::new(Mutex::new(subscribers)) };
+  let receiver = sender.subscribe();
+  (sender, receiver)
+}
+
+impl<T: Clone> BroadcastSend<T> {
+  // Sends a clone of `msg` to every subscribed receiver and returns how many
+  // there were. Fails, handing `msg` back, if there are none.
+
+  pub fn send(&self, msg: T) -> Result<usize, SendError<T>> {
+    let mut subscribers = self.subscribers.lock().unwrap();
+    // Sending only fails once the receiver is gone, so drop those subscribers.
+    subscribers.senders.retain_mut(|(s, _)| s.try_send(msg.clone()).is_ok());
+    match subscribers.senders.len() {
+      0 => Err(SendError(msg)),
+      n => {
+        subscribers.seq += 1;
+        Ok(n)
+      }
+    }
+  }
+
+  // A new receiver for the messages sent from now on.
+
+  pub fn subscribe(&self) -> BroadcastRecv<T> {
+    let (s, r) = new_multi_chan();
+    let mut subscribers = self.subscribers.lock().unwrap();
+    let cursor = Arc::new(Cursor { consumed: Mutex::new(Some(subscribers.seq)), cond: Condvar::new() });
+    subscribers.senders.push((s, cursor.clone()));
+    BroadcastRecv { receiver: Some(r), cursor }
+  }
+
+  // The number of subscribed receivers, including ones that were dropped
+  // since the last `send`.
+
+  pub fn receiver_count(&self) -> usize {
+    self.subscribers.lock().unwrap().senders.len()
+  }
+
+  // The number of the last message sent by this sender or any of its clones,
+  // or 0 if none has been sent yet.
+
+  pub fn seq(&self) -> u64 {
+    self.subscribers.lock().unwrap().seq
+  }
+
+  // Blocks until every receiver has received the messages up to number `seq`,
+  // or has been dropped.
+
+  pub fn wait_consumed(&self, seq: u64) {
+    let cursors: Vec<_> = self.subscribers.lock().unwrap().senders.iter().map(|(_, c)| c.clone()).collect();
+    for cursor in cursors {
+      cursor.wait_for(seq);
+    }
+  }
+}
+
+impl<T> Clone for BroadcastSend<T> {
+  fn clone(&self) -> Self {
+    BroadcastSend { subscribers: self.subscribers.clone() }
+  }
+}
+
+impl<T> BroadcastRecv<T> {
+  // Blocks until the next message arrives. Returns `None` once every sender is
+  // gone and all messages have been received.
+
+  pub fn recv(&mut self) -> Option<T> {
+    let (msg, next) = self.receiver.take()?.recv()?;
+    self.receiver = Some(next);
+    self.cursor.advance();
+    Some(msg)
+  }
+
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let msg = match &mut self.receiver {
+      Some(r) => r.try_recv()?,
+      None => return Err(TryRecvError::Disconnected),
+    };
+    self.cursor.advance();
+    Ok(msg)
+  }
+}
+
+impl<T> Drop for BroadcastRecv<T> {
+  fn drop(&mut self) {
+    *self.cursor.consumed.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    self.cursor.cond.notify_all();
+  }
+}
+
+impl<T> Iterator for BroadcastRecv<T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.recv()
+  }
+}
+
+/* Consumer groups.
+
+A group is a set of receivers that share the messages between them: each
+message goes to one member of the group, while every group, like every plain
+receiver, sees all messages. A pool of workers can thus split the work on a
+stream that other parts of the program follow in full.
+
+`subscribe_group()` starts a new group and returns its first member. The group
+is an MPMC queue fed by a subscription of its own, so further members join by
+cloning the `mpmc::Receiver`. A thread forwards the subscription to the queue;
+it stops once the stream has ended, which disconnects the members, or once
+every member is gone. For `wait_consumed`, a group has read a message as soon
+as it is in its queue. */
+
+impl<T: Clone + Send + 'static> BroadcastSend<T> {
+  pub fn subscribe_group(&self) -> mpmc::Receiver<T> {
+    let subscription = self.subscribe();
+    let (s, r) = mpmc::channel();
+    threads::spawn("broadcast-group".to_string(), move || {
+      for msg in subscription {
+        // Sending only fails once every member is gone.
+        if s.send(msg).is_err() {
+          return;
+        }
+      }
+    });
+    r
+  }
+}
+
+#[test]
+fn test_broadcast_every_receiver_sees_every_message() {
+  use std::thread;
+
+  let (s, r) = channel();
+  let receivers: Vec<_> = (0..3).map(|_| s.subscribe()).chain(Some(r)).collect();
+  let handles: Vec<_> = receivers.into_iter().map(|r| thread::spawn(move || r.collect::<Vec<_>>())).collect();
+  for i in 0..5 {
+    assert_eq!(s.send(i), Ok(4));
+  }
+  drop(s);
+  for h in handles {
+    assert_eq!(h.join().unwrap(), vec![0, 1, 2, 3, 4]);
+  }
+}
+
+#[test]
+fn test_broadcast_subscribe_later() {
+  let (s, mut first) = channel();
+  s.send("before").unwrap();
+  let mut second = s.subscribe();
+  s.send("after").unwrap();
+  assert_eq!(first.try_recv(), Ok("before"));
+  assert_eq!(first.try_recv(), Ok("after"));
+  assert_eq!(second.try_recv(), Ok("after"));
+  assert_eq!(second.try_recv(), Err(TryRecvError::Empty));
+
+  drop(first);
+  drop(second);
+  assert_eq!(s.receiver_count(), 2);
+  assert_eq!(s.send("nobody"), Err(SendError("nobody")));
+  assert_eq!(s.receiver_count(), 0);
+}
+
+#[test]
+fn test_broadcast_wait_consumed() {
+  use std::thread;
+  use std::time::Duration;
+
+  let (s, mut fast) = channel();
+  let mut slow = s.subscribe();
+  assert_eq!(s.seq(), 0);
+  s.send(1).unwrap();
+  s.send(2).unwrap();
+  let seq = s.seq();
+  assert_eq!(seq, 2);
+  assert_eq!((fast.try_recv(), fast.try_recv()), (Ok(1), Ok(2)));
+
+  // Waits for the slow receiver, one message at a time.
+  let waiter = s.clone();
+  let h = thread::spawn(move || waiter.wait_consumed(seq));
+  thread::sleep(Duration::from_millis(30));
+  assert!(!h.is_finished());
+  assert_eq!(slow.try_recv(), Ok(1));
+  thread::sleep(Duration::from_millis(30));
+  assert!(!h.is_finished());
+  assert_eq!(slow.recv(), Some(2));
+  h.join().unwrap();
+
+  // A receiver that subscribed later counts as having read what came before,
+  // and one that was dropped is not waited for.
+  let mut late = s.subscribe();
+  s.send(3).unwrap();
+  assert_eq!((fast.recv(), late.recv()), (Some(3), Some(3)));
+  drop(slow);
+  s.wait_consumed(3);
+}
+
+#[test]
+fn test_broadcast_groups_share_messages() {
+  use std::thread;
+
+  let (s, all) = channel();
+  let groups = [s.subscribe_group(), s.subscribe_group()];
+  let members: Vec<Vec<_>> = groups.iter().map(|g| {
+    (0..3).map(|_| {
+      let r = g.clone();
+      thread::spawn(move || r.iter().collect::<Vec<_>>())
+    }).collect()
+  }).collect();
+  drop(groups);
+  for i in 0..100 {
+    s.send(i).unwrap();
+  }
+  drop(s);
+
+  assert_eq!(all.collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+  // Each group sees every message exactly once, spread over its members.
+  for group in members {
+    let mut got: Vec<_> = group.into_iter().flat_map(|h| h.join().unwrap()).collect();
+    got.sort();
+    assert_eq!(got, (0..100).collect::<Vec<_>>());
+  }
+}
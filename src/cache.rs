@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::oneshot::{new_chan, Send};
+
+/* Caches shared between threads. */
+
+/* A `Memo<K, V>` caches the results of an expensive function of a key.
+
+When several threads ask for the same missing key at the same time, only the
+first one computes the value. The others each leave the sender of a one-shot
+channel in the cache entry and wait on its receiver, and the computing thread
+sends them the value when it is done. If the computation panics, the entry is
+removed, which drops the senders, and the waiting threads try again (one of
+them then computes the value itself).
+
+Values expire after an optional time to live. When more than `capacity` values
+are cached, the least recently used one is evicted. */
+
+enum Entry<V> {
+  Ready { value: V, inserted: Instant, last_used: u64 },
+  Pending(Vec<Send<V>>),
+}
+
+struct MemoState<K, V> {
+  entries: HashMap<K, Entry<V>>,
+  ready: usize,
+  // Counts lookups, to tell which value was used least recently.
+  clock: u64,
+}
+
+pub struct Memo<K, V> {
+  state: Mutex<MemoState<K, V>>,
+  capacity: usize,
+  ttl: Option<Duration>,
+}
+
+// Removes the pending entry of a computation that panicked, which wakes up the
+// threads waiting for it.
+
+struct Computing<'a, K: Hash + Eq, V> {
+  memo: &'a Memo<K, V>,
+  key: &'a K,
+}
+
+impl<K: Hash + Eq, V> Drop for Computing<'_, K, V> {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      let mut state = self.memo.state.lock().unwrap_or_else(|e| e.into_inner());
+      if let Some(Entry::Pending(_)) = state.entries.get(self.key) {
+        state.entries.remove(self.key);
+      }
+    }
+  }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Memo<K, V> {
+  pub fn new(capacity: usize) -> Memo<K, V> {
+    Memo::with_ttl(capacity, None)
+  }
+
+  // A memo whose values expire `ttl` after they were computed, if given.
+
+  pub fn with_ttl(capacity: usize, ttl: Option<Duration>) -> Memo<K, V> {
+    assert!(capacity > 0, "Memo: capacity must be positive");
+    let state = MemoState { entries: HashMap::new(), ready: 0, clock: 0 };
+    Memo { state: Mutex::new(state), capacity, ttl }
+  }
+
+  // The number of cached values, not counting computations in progress.
+
+  pub fn len(&self) -> usize {
+    self.state.lock().unwrap().ready
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // Forgets the cached value for `key`. A computation in progress is not
+  // affected.
+
+  pub fn invalidate(&self, key: &K) {
+    let mut state = self.state.lock().unwrap();
+    if let Some(Entry::Ready { .. }) = state.entries.get(key) {
+      state.entries.remove(key);
+      state.ready -= 1;
+    }
+  }
+
+  // Returns the cached value for `key`, or waits for the thread that is
+  // computing it, or computes it with `f`.
+
+  pub fn get_or_compute(&self, key: K, f: impl FnOnce() -> V) -> V {
+    let mut f = Some(f);
+    loop {
+      let mut state = self.state.lock().unwrap();
+      state.clock += 1;
+      let now = state.clock;
+      match state.entries.get_mut(&key) {
+        Some(Entry::Ready { value, inserted, last_used }) if !self.is_expired(*inserted) => {
+          *last_used = now;
+          return value.clone();
+        }
+        Some(Entry::Pending(waiters)) => {
+          let (s, r) = new_chan();
+          waiters.push(s);
+          drop(state);
+          match r.recv() {
+            Ok(value) => return value,
+            // The computation panicked; try again.
+            Err(_) => continue,
+          }
+        }
+        Some(Entry::Ready { .. }) => state.ready -= 1,
+        None => {}
+      }
+
+      state.entries.insert(key.clone(), Entry::Pending(Vec::new()));
+      drop(state);
+      let computing = Computing { memo: self, key: &key };
+      // `f` is only taken here, and we return right after.
+      let value = (f.take().unwrap())();
+      drop(computing);
+      self.finish(key, value.clone());
+      return value;
+    }
+  }
+
+  fn is_expired(&self, inserted: Instant) -> bool {
+    self.ttl.is_some_and(|ttl| inserted.elapsed() >= ttl)
+  }
+
+  // Caches the computed value and hands it to the waiting threads.
+
+  fn finish(&self, key: K, value: V) {
+    let mut state = self.state.lock().unwrap();
+    let last_used = state.clock;
+    let entry = Entry::Ready { value: value.clone(), inserted: Instant::now(), last_used };
+    let waiters = match state.entries.insert(key, entry) {
+      Some(Entry::Pending(waiters)) => waiters,
+      _ => Vec::new(),
+    };
+    state.ready += 1;
+    self.evict(&mut state);
+    drop(state);
+
+    for w in waiters {
+      // A waiter cannot go away while waiting, but ignore it if it did.
+      let _ = w.send(value.clone());
+    }
+  }
+
+  // Drops expired values, then the least recently used ones until at most
+  // `capacity` are left.
+
+  fn evict(&self, state: &mut MemoState<K, V>) {
+    if state.ready <= self.capacity {
+      return;
+    }
+    let before = state.entries.len();
+    state.entries.retain(|_, e| !matches!(e, Entry::Ready { inserted, .. } if self.is_expired(*inserted)));
+    state.ready -= before - state.entries.len();
+
+    while state.ready > self.capacity {
+      let oldest = state.entries.iter()
+        .filter_map(|(k, e)| match e {
+          Entry::Ready { last_used, .. } => Some((*last_used, k)),
+          Entry::Pending(_) => None,
+        })
+        .min_by_key(|&(last_used, _)| last_used)
+        .map(|(_, k)| k.clone());
+      match oldest {
+        Some(k) => {
+          state.entries.remove(&k);
+          state.ready -= 1;
+        }
+        None => break,
+      }
+    }
+  }
+}
+
+#[test]
+fn test_memo_computes_once() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  let memo = Arc::new(Memo::new(10));
+  let calls = Arc::new(AtomicUsize::new(0));
+  let handles: Vec<_> = (0..8).map(|_| {
+    let memo = memo.clone();
+    let calls = calls.clone();
+    thread::spawn(move || {
+      memo.get_or_compute("key", || {
+        calls.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        42
+      })
+    })
+  }).collect();
+  for h in handles {
+    assert_eq!(h.join().unwrap(), 42);
+  }
+  assert_eq!(calls.load(Ordering::SeqCst), 1);
+  assert_eq!(memo.len(), 1);
+}
+
+#[test]
+fn test_memo_eviction() {
+  let memo = Memo::with_ttl(2, Some(Duration::from_millis(50)));
+  memo.get_or_compute(1, || "one");
+  memo.get_or_compute(2, || "two");
+  // Using 1 makes 2 the least recently used value.
+  assert_eq!(memo.get_or_compute(1, || unreachable!()), "one");
+  memo.get_or_compute(3, || "three");
+  assert_eq!(memo.len(), 2);
+  assert_eq!(memo.get_or_compute(2, || "two again"), "two again");
+
+  thread::sleep(Duration::from_millis(60));
+  assert_eq!(memo.get_or_compute(1, || "fresh"), "fresh");
+  memo.invalidate(&1);
+  assert_eq!(memo.get_or_compute(1, || "recomputed"), "recomputed");
+}
+
+#[test]
+fn test_memo_panicking_computation() {
+  use std::sync::Arc;
+
+  let memo = Arc::new(Memo::new(10));
+  let m = memo.clone();
+  let failing = thread::spawn(move || {
+    m.get_or_compute(1, || -> i32 {
+      thread::sleep(Duration::from_millis(50));
+      panic!("computation failed")
+    })
+  });
+  thread::sleep(Duration::from_millis(10));
+  // This waits for the failing computation, then computes the value itself.
+  assert_eq!(memo.get_or_compute(1, || 7), 7);
+  assert!(failing.join().is_err());
+}
+
+/* A `ShardedLru<K, V>` is a bounded least-recently-used cache for many threads.
+
+The keys are spread over several shards by their hash, each with its own lock,
+its own part of the capacity and its own LRU order, so threads working on
+different keys rarely wait for each other. Eviction is therefore only
+approximately least-recently-used across the whole cache: a shard evicts its
+own oldest entry when it is full, even if other shards hold older ones.
+
+Each shard orders its entries by a counter that is bumped on every access, in
+a `BTreeMap` from counter to key, so the oldest entry is the first one. */
+
+struct Shard<K, V> {
+  capacity: usize,
+  entries: HashMap<K, (V, u64)>,
+  order: BTreeMap<u64, K>,
+  clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Shard<K, V> {
+  // Marks the entry for `key` as just used. Returns whether there is one.
+
+  fn touch(&mut self, key: &K) -> bool {
+    self.clock += 1;
+    let clock = self.clock;
+    match self.entries.get_mut(key) {
+      Some((_, used)) => {
+        let key = self.order.remove(used).unwrap();
+        *used = clock;
+        self.order.insert(clock, key);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+pub struct ShardedLru<K, V> {
+  shards: Vec<Mutex<Shard<K, V>>>,
+  hasher: RandomState,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedLru<K, V> {
+  // A cache for at most `capacity` entries, split over `shards` shards, or over
+  // `capacity` shards if that is fewer. The capacity is divided as evenly as
+  // possible, so the shards hold exactly `capacity` entries when all are full.
+
+  pub fn new(capacity: usize, shards: usize) -> ShardedLru<K, V> {
+    assert!(capacity > 0 && shards > 0, "ShardedLru: capacity and shards must be positive");
+    let n = shards.min(capacity);
+    let shards = (0..n)
+      .map(|i| {
+        let capacity = capacity / n + usize::from(i < capacity % n);
+        Mutex::new(Shard { capacity, entries: HashMap::new(), order: BTreeMap::new(), clock: 0 })
+      })
+      .collect();
+    ShardedLru { shards, hasher: RandomState::new() }
+  }
+
+  fn shard(&self, key: &K) -> &Mutex<Shard<K, V>> {
+    let i = self.hasher.hash_one(key) as usize % self.shards.len();
+    &self.shards[i]
+  }
+
+  // A copy of the value for `key`, which becomes the most recently used entry
+  // of its shard.
+
+  pub fn get(&self, key: &K) -> Option<V> {
+    let mut shard = self.shard(key).lock().unwrap();
+    if shard.touch(key) {
+      shard.entries.get(key).map(|(v, _)| v.clone())
+    } else {
+      None
+    }
+  }
+
+  // Inserts or replaces the value for `key` and returns the old one. Evicts the
+  // least recently used entry of the shard if it is full.
+
+  pub fn insert(&self, key: K, value: V) -> Option<V> {
+    let mut shard = self.shard(&key).lock().unwrap();
+    if shard.touch(&key) {
+      let entry = shard.entries.get_mut(&key).unwrap();
+      return Some(std::mem::replace(&mut entry.0, value));
+    }
+    if shard.entries.len() >= shard.capacity {
+      if let Some((_, oldest)) = shard.order.pop_first() {
+        shard.entries.remove(&oldest);
+      }
+    }
+    let clock = shard.clock;
+    shard.order.insert(clock, key.clone());
+    shard.entries.insert(key, (value, clock));
+    None
+  }
+
+  pub fn remove(&self, key: &K) -> Option<V> {
+    let mut shard = self.shard(key).lock().unwrap();
+    let (value, used) = shard.entries.remove(key)?;
+    shard.order.remove(&used);
+    Some(value)
+  }
+
+  // The number of entries. Other threads may change it at any time.
+
+  pub fn len(&self) -> usize {
+    self.shards.iter().map(|s| s.lock().unwrap().entries.len()).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[test]
+fn test_sharded_lru_evicts_least_recently_used() {
+  // With one shard, the order is exact.
+  let lru = ShardedLru::new(2, 1);
+  lru.insert("a", 1);
+  lru.insert("b", 2);
+  assert_eq!(lru.get(&"a"), Some(1));
+  lru.insert("c", 3);
+  assert_eq!(lru.get(&"b"), None);
+  assert_eq!(lru.insert("a", 10), Some(1));
+  assert_eq!(lru.len(), 2);
+  assert_eq!(lru.remove(&"c"), Some(3));
+  assert_eq!(lru.get(&"a"), Some(10));
+  assert_eq!(lru.len(), 1);
+}
+
+#[test]
+fn test_sharded_lru_from_many_threads() {
+  use std::sync::Arc;
+
+  let lru = Arc::new(ShardedLru::new(64, 8));
+  let handles: Vec<_> = (0..4).map(|t| {
+    let lru = lru.clone();
+    thread::spawn(move || {
+      for i in 0..1000 {
+        let key = (t * 1000 + i) % 100;
+        if lru.get(&key).is_none() {
+          lru.insert(key, key * 2);
+        }
+      }
+    })
+  }).collect();
+  for h in handles {
+    h.join().unwrap();
+  }
+  assert!(lru.len() <= 64);
+  for key in 0..100 {
+    assert!(lru.get(&key).is_none_or(|v| v == key * 2));
+  }
+}
+
+#[test]
+fn test_sharded_lru_total_capacity() {
+  for (capacity, shards) in [(10, 4), (3, 8), (64, 8)] {
+    let lru = ShardedLru::new(capacity, shards);
+    for key in 0..1000 {
+      lru.insert(key, ());
+      assert!(lru.len() <= capacity);
+    }
+  }
+}
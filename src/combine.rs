@@ -0,0 +1,137 @@
+use crate::errors::TryRecvError;
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::select::Select;
+use crate::threads;
+
+/* Combinators for multi-shot receivers.
+
+Each combinator runs on a thread of its own that forwards messages from its
+inputs to its outputs. The thread ends once its inputs are closed, or once
+nobody receives its output anymore; it only notices the latter when it next
+tries to forward a message. */
+
+
+/* `merge` interleaves the messages of several receivers into one stream. It
+waits on all inputs at once with a `Select`, and after forwarding a message
+from one input it moves that input to the back of the list, so a busy input
+cannot starve the others. Messages from the same input keep their order. An
+input that is closed is dropped from the list, and the merged stream is closed
+once all inputs are. */
+
+pub fn merge<T: Send + 'static>(inputs: Vec<MultiRecv<T>>) -> MultiRecv<T> {
+  let (output, merged) = new_multi_chan();
+  threads::spawn("merge".to_string(), move || forward_merged(inputs, output));
+  merged
+}
+
+fn forward_merged<T>(mut inputs: Vec<MultiRecv<T>>, mut output: MultiSend<T>) {
+  while !inputs.is_empty() {
+    let i = {
+      let mut select = Select::new();
+      for r in &inputs {
+        select.add(r);
+      }
+      select.ready()
+    };
+    match inputs[i].try_recv() {
+      Ok(msg) => {
+        output = match output.send(msg) {
+          Ok(output) => output,
+          Err(_) => return,
+        };
+        inputs[i..].rotate_left(1);
+      }
+      // Not reached: `Select` only reports an input once it has a message or is
+      // closed.
+      Err(TryRecvError::Empty) => {}
+      Err(TryRecvError::Disconnected) => {
+        inputs.remove(i);
+      }
+    }
+  }
+}
+
+/* `tee` duplicates a stream: every message goes to both outputs. The outputs
+are unbounded, so a slow consumer on one side does not hold up the other. If
+one output is dropped, the other still gets every message. */
+
+pub fn tee<T: Clone + Send + 'static>(input: MultiRecv<T>) -> (MultiRecv<T>, MultiRecv<T>) {
+  let (left, left_r) = new_multi_chan();
+  let (right, right_r) = new_multi_chan();
+  threads::spawn("tee".to_string(), move || forward_tee(input, [Some(left), Some(right)]));
+  (left_r, right_r)
+}
+
+fn forward_tee<T: Clone>(input: MultiRecv<T>, mut outputs: [Option<MultiSend<T>>; 2]) {
+  for msg in input {
+    for output in &mut outputs {
+      // Stop sending to an output once its receiver is gone.
+      if output.as_mut().is_some_and(|o| o.try_send(msg.clone()).is_err()) {
+        *output = None;
+      }
+    }
+    if outputs.iter().all(Option::is_none) {
+      return;
+    }
+  }
+}
+
+#[test]
+fn test_merge_all_messages_in_source_order() {
+  use std::thread;
+
+  let inputs: Vec<_> = (0..3).map(|i| {
+    let (mut s, r) = new_multi_chan();
+    thread::spawn(move || {
+      for j in 0..20 {
+        s = s.send((i, j)).unwrap();
+      }
+    });
+    r
+  }).collect();
+  let received: Vec<_> = merge(inputs).into_iter().collect();
+  assert_eq!(received.len(), 60);
+  for i in 0..3 {
+    let from_i: Vec<_> = received.iter().filter(|m| m.0 == i).map(|m| m.1).collect();
+    assert_eq!(from_i, (0..20).collect::<Vec<_>>());
+  }
+}
+
+#[test]
+fn test_merge_is_fair() {
+  // Both inputs have all their messages ready from the start.
+  let (mut busy, r1) = new_multi_chan();
+  let (quiet, r2) = new_multi_chan();
+  for _ in 0..10 {
+    busy = busy.send("busy").unwrap();
+  }
+  quiet.send("quiet").unwrap().close();
+  busy.close();
+  let received: Vec<_> = merge(vec![r1, r2]).into_iter().collect();
+  assert_eq!(received.len(), 11);
+  assert!(received[..2].contains(&"quiet"));
+}
+
+#[test]
+fn test_tee() {
+  use std::thread;
+
+  let (mut s, r) = new_multi_chan();
+  for i in 0..5 {
+    s = s.send(i).unwrap();
+  }
+  s.close();
+  let (log, main) = tee(r);
+  let logger = thread::spawn(move || log.into_iter().collect::<Vec<_>>());
+  assert_eq!(main.into_iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+  assert_eq!(logger.join().unwrap(), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_tee_one_side_dropped() {
+  let (s, r) = new_multi_chan();
+  let (left, right) = tee(r);
+  drop(left);
+  s.send("a").unwrap().send("b").unwrap().close();
+  assert_eq!(right.into_iter().collect::<Vec<_>>(), vec!["a", "b"]);
+}
@@ -0,0 +1,96 @@
+use crate::errors::{SendError, TryRecvError};
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+
+/* Duplex channels.
+
+A duplex channel connects two endpoints that can both send and receive, such
+as a main thread and a worker that answers it. It is made of two multi-shot
+channels, one in each direction. An `Endpoint<T, U>` sends `T`s to its peer
+and receives `U`s from it, so the peer is an `Endpoint<U, T>`.
+
+Either direction can be closed on its own with `close`; dropping an endpoint
+closes its sending direction. */
+
+pub struct Endpoint<T, U> {
+  sender: MultiSend<T>,
+  // `None` once the peer has closed its sending direction.
+  receiver: Option<MultiRecv<U>>,
+}
+
+pub fn pair<T, U>() -> (Endpoint<T, U>, Endpoint<U, T>) {
+  let (s1, r1) = new_multi_chan();
+  let (s2, r2) = new_multi_chan();
+  (Endpoint { sender: s1, receiver: Some(r2) }, Endpoint { sender: s2, receiver: Some(r1) })
+}
+
+impl<T, U> Endpoint<T, U> {
+  // Sends `msg` to the peer. Fails, handing `msg` back, if the peer is gone.
+
+  pub fn send(&mut self, msg: T) -> Result<(), SendError<T>> {
+    // The channel is unbounded, so this only fails if the peer is gone.
+    self.sender.try_send(msg).map_err(|e| SendError(e.into_inner()))
+  }
+
+  // Blocks until the peer sends a message. Returns `None` once the peer has
+  // closed its sending direction.
+
+  pub fn recv(&mut self) -> Option<U> {
+    let (msg, next) = self.receiver.take()?.recv()?;
+    self.receiver = Some(next);
+    Some(msg)
+  }
+
+  pub fn try_recv(&mut self) -> Result<U, TryRecvError> {
+    match &mut self.receiver {
+      Some(r) => r.try_recv(),
+      None => Err(TryRecvError::Disconnected),
+    }
+  }
+
+  // Closes the sending direction, so the peer's `recv` returns `None` once it
+  // has received everything. This endpoint can still receive.
+
+  pub fn close(self) -> MultiRecv<U> {
+    match self.receiver {
+      Some(r) => r,
+      // The peer already closed its direction; hand out a closed receiver.
+      None => new_multi_chan().1,
+    }
+  }
+
+  // Splits the endpoint into its two channel ends.
+
+  pub fn split(self) -> (MultiSend<T>, Option<MultiRecv<U>>) {
+    (self.sender, self.receiver)
+  }
+}
+
+#[test]
+fn test_duplex_worker_answers() {
+  use std::thread;
+
+  let (mut main, mut worker) = pair::<u32, String>();
+  let h = thread::spawn(move || {
+    while let Some(n) = worker.recv() {
+      worker.send(format!("got {}", n)).unwrap();
+    }
+  });
+  for n in 0..3 {
+    main.send(n).unwrap();
+    assert_eq!(main.recv(), Some(format!("got {}", n)));
+  }
+  // Closing the main thread's direction ends the worker's loop, and the worker
+  // dropping its endpoint closes the other direction.
+  let rest = main.close();
+  h.join().unwrap();
+  assert!(rest.recv().is_none());
+}
+
+#[test]
+fn test_duplex_peer_gone() {
+  let (mut a, b) = pair::<i32, i32>();
+  drop(b);
+  assert_eq!(a.send(1), Err(SendError(1)));
+  assert_eq!(a.try_recv(), Err(TryRecvError::Disconnected));
+  assert_eq!(a.recv(), None);
+}
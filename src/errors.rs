@@ -0,0 +1,337 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::multishot::MultiSend;
+
+/* Error types of the channels, and error collection across threads.
+
+The channel errors mirror the ones of `std::sync::mpsc`:
+
+ - `SendError<T>`: the message could not be delivered and is handed back.
+ - `TrySendError<T>`: the message could not be delivered right now, or ever.
+ - `MultiSendError<T>`: like `TrySendError<T>`, for the multi-shot `send`,
+   which also hands back the sender if it can be used again.
+ - `RecvError`: no message will ever arrive.
+ - `TryRecvError`: no message is available right now, or ever.
+ - `RecvTimeoutError`: no message arrived in time, or none ever will. */
+
+// Returned by `send` when the message cannot be delivered. The message is
+// handed back to the caller.
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+// Not derived, so that `SendError<T>` is `Debug` (and `unwrap` works) for any
+// message type.
+
+impl<T> fmt::Debug for SendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("SendError { .. }")
+  }
+}
+
+impl<T> fmt::Display for SendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "sending on a disconnected channel")
+  }
+}
+
+impl<T> Error for SendError<T> {}
+
+// Returned by `try_send`. Either way the message is handed back.
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+  Full(T),
+  Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+  pub fn into_inner(self) -> T {
+    match self {
+      TrySendError::Full(msg) | TrySendError::Disconnected(msg) => msg,
+    }
+  }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TrySendError::Full(_) => f.write_str("Full(..)"),
+      TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+    }
+  }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TrySendError::Full(_) => write!(f, "sending on a full channel"),
+      TrySendError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+    }
+  }
+}
+
+impl<T> Error for TrySendError<T> {}
+
+// Returned by `MultiSend::send`. A full channel only refuses the message under
+// `OverflowPolicy::Fail`; the sender is then handed back along with it, so the
+// caller can try again later.
+
+pub enum MultiSendError<T> {
+  Full(T, MultiSend<T>),
+  Disconnected(T),
+}
+
+impl<T> MultiSendError<T> {
+  pub fn into_inner(self) -> T {
+    match self {
+      MultiSendError::Full(msg, _) | MultiSendError::Disconnected(msg) => msg,
+    }
+  }
+}
+
+impl<T> fmt::Debug for MultiSendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MultiSendError::Full(..) => f.write_str("Full(..)"),
+      MultiSendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+    }
+  }
+}
+
+impl<T> fmt::Display for MultiSendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MultiSendError::Full(..) => write!(f, "sending on a full channel"),
+      MultiSendError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+    }
+  }
+}
+
+impl<T> Error for MultiSendError<T> {}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+  fn from(SendError(msg): SendError<T>) -> TrySendError<T> {
+    TrySendError::Disconnected(msg)
+  }
+}
+
+// Returned by `recv` when the channel is disconnected and no message will
+// arrive.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+  Disconnected,
+}
+
+impl fmt::Display for RecvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RecvError::Disconnected => write!(f, "receiving on a disconnected channel"),
+    }
+  }
+}
+
+impl Error for RecvError {}
+
+// Returned by `try_recv`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+  Empty,
+  Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+      TryRecvError::Disconnected => write!(f, "receiving on a disconnected channel"),
+    }
+  }
+}
+
+impl Error for TryRecvError {}
+
+impl From<RecvError> for TryRecvError {
+  fn from(_: RecvError) -> TryRecvError {
+    TryRecvError::Disconnected
+  }
+}
+
+// Returned by `recv_timeout` and `recv_deadline`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+  Timeout,
+  Disconnected,
+}
+
+impl fmt::Display for RecvTimeoutError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RecvTimeoutError::Timeout => write!(f, "timed out waiting on channel"),
+      RecvTimeoutError::Disconnected => write!(f, "channel is empty and sending half is closed"),
+    }
+  }
+}
+
+impl Error for RecvTimeoutError {}
+
+impl From<RecvError> for RecvTimeoutError {
+  fn from(_: RecvError) -> RecvTimeoutError {
+    RecvTimeoutError::Disconnected
+  }
+}
+
+/* A `Collector<E>` is cloned into every worker. Workers `push` the errors they
+run into, and once all of them are done the owner calls `finish()`, which
+returns `Ok(())` if nothing went wrong or an `AggregateError` listing every
+error together with the thread (and optionally the task) it came from. */
+
+// One collected error, together with where it came from.
+
+#[derive(Debug)]
+pub struct Attributed<E> {
+  pub thread: String,
+  pub task: Option<String>,
+  pub error: E,
+}
+
+impl<E: fmt::Display> fmt::Display for Attributed<E> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.task {
+      Some(task) => write!(f, "[{} / {}] {}", self.thread, task, self.error),
+      None => write!(f, "[{}] {}", self.thread, self.error),
+    }
+  }
+}
+
+// The error returned by `Collector::finish` when at least one error was pushed.
+
+#[derive(Debug)]
+pub struct AggregateError<E> {
+  pub errors: Vec<Attributed<E>>,
+}
+
+impl<E: fmt::Display> fmt::Display for AggregateError<E> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} error(s) collected", self.errors.len())?;
+    for e in &self.errors {
+      write!(f, "\n  {}", e)?;
+    }
+    Ok(())
+  }
+}
+
+impl<E: Error> Error for AggregateError<E> {}
+
+// The handle shared by the workers. Cloning it is cheap, all clones push into
+// the same list.
+
+pub struct Collector<E> {
+  errors: Arc<Mutex<Vec<Attributed<E>>>>,
+}
+
+impl<E> Clone for Collector<E> {
+  fn clone(&self) -> Self {
+    Collector { errors: Arc::clone(&self.errors) }
+  }
+}
+
+impl<E> Default for Collector<E> {
+  fn default() -> Self {
+    Collector::new()
+  }
+}
+
+// Name of the calling thread, falling back to its id for unnamed threads.
+
+fn current_thread_name() -> String {
+  let t = thread::current();
+  match t.name() {
+    Some(name) => name.to_string(),
+    None => format!("{:?}", t.id()),
+  }
+}
+
+impl<E> Collector<E> {
+  pub fn new() -> Collector<E> {
+    Collector { errors: Arc::new(Mutex::new(Vec::new())) }
+  }
+
+  // Records `error`, attributed to the calling thread.
+
+  pub fn push(&self, error: E) {
+    self.record(None, error)
+  }
+
+  // Records `error`, attributed to the calling thread and the given task.
+
+  pub fn push_task(&self, task: impl Into<String>, error: E) {
+    self.record(Some(task.into()), error)
+  }
+
+  fn record(&self, task: Option<String>, error: E) {
+    let entry = Attributed { thread: current_thread_name(), task, error };
+    self.errors.lock().unwrap().push(entry);
+  }
+
+  pub fn len(&self) -> usize {
+    self.errors.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // Takes all errors collected so far. Errors pushed by clones that are still
+  // alive after this call are not part of the result.
+
+  pub fn finish(self) -> Result<(), AggregateError<E>> {
+    let errors = std::mem::take(&mut *self.errors.lock().unwrap());
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(AggregateError { errors })
+    }
+  }
+}
+
+#[test]
+fn test_collector_ok() {
+  let c: Collector<String> = Collector::new();
+  let handles: Vec<_> = (0..4).map(|_| {
+    let c = c.clone();
+    thread::spawn(move || drop(c))
+  }).collect();
+  for h in handles {
+    h.join().unwrap();
+  }
+  assert!(c.finish().is_ok());
+}
+
+#[test]
+fn test_collector_attribution() {
+  let c = Collector::new();
+  let handles: Vec<_> = (0..4).map(|i| {
+    let c = c.clone();
+    thread::Builder::new().name(format!("worker-{}", i)).spawn(move || {
+      if i % 2 == 0 {
+        c.push_task(format!("task-{}", i), format!("failed {}", i));
+      }
+    }).unwrap()
+  }).collect();
+  for h in handles {
+    h.join().unwrap();
+  }
+  let mut errors = c.finish().unwrap_err().errors;
+  errors.sort_by(|a, b| a.thread.cmp(&b.thread));
+  assert_eq!(errors.len(), 2);
+  assert_eq!(errors[0].thread, "worker-0");
+  assert_eq!(errors[0].task.as_deref(), Some("task-0"));
+  assert_eq!(errors[1].error, "failed 2");
+}
@@ -0,0 +1,139 @@
+use std::fmt::Debug;
+
+use crate::log::LogWriter;
+use crate::multishot::{MultiRecv, MultiSend};
+
+/* A skeleton for event-driven components.
+
+The component is written as a `StateMachine`: a state type with a `step`
+function that takes one event and returns the next state together with the
+actions to perform. `run` feeds it the events of a receiver one at a time and
+sends the actions on to a sender, so the component itself never touches a
+channel and can be tested by calling `step` directly.
+
+`run_traced` also appends every transition to a log, rendered with `Debug`.
+The log can be shared with any number of readers, e.g. a debugging view that
+joins late and still sees the whole history. */
+
+pub trait StateMachine: Sized {
+  type Event;
+  type Action;
+
+  fn step(self, event: Self::Event) -> (Self, Vec<Self::Action>);
+}
+
+// One step of a traced state machine.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+  pub from: String,
+  pub event: String,
+  pub to: String,
+}
+
+// Runs `machine` on the events of `events` and sends the resulting actions to
+// `actions`. Returns the final state once the events stream is closed, or as
+// soon as the receiver of the actions is gone.
+
+pub fn run<M: StateMachine>(machine: M, events: MultiRecv<M::Event>, actions: MultiSend<M::Action>) -> M {
+  let mut machine = machine;
+  let mut actions = actions;
+  for event in events {
+    let (next, out) = machine.step(event);
+    machine = next;
+    for action in out {
+      actions = match actions.send(action) {
+        Ok(actions) => actions,
+        Err(_) => return machine,
+      };
+    }
+  }
+  machine
+}
+
+// `run`, recording every transition in `trace`.
+
+pub fn run_traced<M>(machine: M, events: MultiRecv<M::Event>, actions: MultiSend<M::Action>, trace: &mut LogWriter<Transition>) -> M
+where
+  M: StateMachine + Debug,
+  M::Event: Debug,
+{
+  run(Traced { machine, trace }, events, actions).machine
+}
+
+// A state machine that records the transitions of the one it wraps.
+
+struct Traced<'a, M> {
+  machine: M,
+  trace: &'a mut LogWriter<Transition>,
+}
+
+impl<M> StateMachine for Traced<'_, M>
+where
+  M: StateMachine + Debug,
+  M::Event: Debug,
+{
+  type Event = M::Event;
+  type Action = M::Action;
+
+  fn step(self, event: M::Event) -> (Self, Vec<M::Action>) {
+    let (from, rendered) = (format!("{:?}", self.machine), format!("{:?}", event));
+    let (machine, actions) = self.machine.step(event);
+    self.trace.push(Transition { from, event: rendered, to: format!("{:?}", machine) });
+    (Traced { machine, trace: self.trace }, actions)
+  }
+}
+
+#[test]
+fn test_fsm_run() {
+  use crate::log::SharedLog;
+  use crate::multishot::new_multi_chan;
+
+  #[derive(Debug, PartialEq)]
+  enum Door {
+    Closed,
+    Open,
+    Locked,
+  }
+
+  #[derive(Debug)]
+  enum DoorEvent {
+    Push,
+    Lock,
+    Unlock,
+  }
+
+  impl StateMachine for Door {
+    type Event = DoorEvent;
+    type Action = &'static str;
+
+    fn step(self, event: DoorEvent) -> (Door, Vec<&'static str>) {
+      match (self, event) {
+        (Door::Closed, DoorEvent::Push) => (Door::Open, vec!["creak"]),
+        (Door::Open, DoorEvent::Push) => (Door::Closed, vec!["slam"]),
+        (Door::Closed, DoorEvent::Lock) => (Door::Locked, vec!["click"]),
+        (Door::Locked, DoorEvent::Unlock) => (Door::Closed, vec!["click"]),
+        (door, _) => (door, vec![]),
+      }
+    }
+  }
+
+  let (s, events) = new_multi_chan();
+  s.send(DoorEvent::Push).unwrap()
+    .send(DoorEvent::Lock).unwrap()
+    .send(DoorEvent::Push).unwrap()
+    .send(DoorEvent::Lock).unwrap()
+    .send(DoorEvent::Push).unwrap()
+    .close();
+  let (actions, r) = new_multi_chan();
+  assert_eq!(run(Door::Closed, events, actions), Door::Locked);
+  assert_eq!(r.into_iter().collect::<Vec<_>>(), vec!["creak", "slam", "click"]);
+
+  let (s, events) = new_multi_chan();
+  s.send(DoorEvent::Lock).unwrap().send(DoorEvent::Unlock).unwrap().close();
+  let (actions, _r) = new_multi_chan();
+  let (mut writer, log) = SharedLog::new();
+  assert_eq!(run_traced(Door::Closed, events, actions, &mut writer), Door::Closed);
+  let trace: Vec<_> = log.iter().map(|t| (t.from.as_str(), t.event.as_str(), t.to.as_str())).collect();
+  assert_eq!(trace, vec![("Closed", "Lock", "Locked"), ("Locked", "Unlock", "Closed")]);
+}
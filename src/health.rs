@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::multishot::{new_bounded_multi_chan_with_policy, new_multi_chan, MultiRecv, MultiSend, OverflowPolicy};
+use crate::threads;
+
+/* Health monitoring: heartbeats for liveness checks, and a registry of the
+health the components report. */
+
+/* Heartbeats for liveness checks.
+
+A component holds a `Beat` and calls `beat()` from its main loop. A monitor
+thread looks at the beats once every `interval` and reports a `Pulse` on the
+returned receiver: `Alive` if the component beat during the interval, `Missed`
+if it did not. A component that is stuck, or that keeps a thread busy in a
+loop that no longer beats, therefore shows up as a run of `Missed` pulses.
+
+Dropping the `Beat` ends the pulse stream, so a component that stops normally
+is not mistaken for a dead one. Dropping the receiver stops the monitor.
+
+The crate has no supervisor to restart dead components; acting on `Missed`
+pulses, e.g. by cancelling and respawning a worker, is up to whoever reads
+them. */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pulse {
+  // The component beat `beats` times during the last interval.
+  Alive { beats: u64 },
+  // The component has not beaten for `missed` intervals in a row.
+  Missed { missed: u32 },
+}
+
+struct State {
+  // Beats since the last pulse.
+  beats: u64,
+  stopped: bool,
+}
+
+struct Repr {
+  state: Mutex<State>,
+  cond: Condvar,
+}
+
+pub struct Beat {
+  repr: Arc<Repr>,
+}
+
+impl Beat {
+  pub fn beat(&self) {
+    self.repr.state.lock().unwrap().beats += 1;
+  }
+}
+
+impl Drop for Beat {
+  fn drop(&mut self) {
+    self.repr.state.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+    self.repr.cond.notify_all();
+  }
+}
+
+pub fn heartbeat(interval: Duration) -> (Beat, MultiRecv<Pulse>) {
+  assert!(!interval.is_zero(), "heartbeat: interval must be positive");
+  let state = State { beats: 0, stopped: false };
+  let repr = Arc::new(Repr { state: Mutex::new(state), cond: Condvar::new() });
+  let (pulses, r) = new_multi_chan();
+  let monitor = repr.clone();
+  threads::spawn("heartbeat".to_string(), move || monitor_beats(&monitor, interval, pulses));
+  (Beat { repr }, r)
+}
+
+// Reports a pulse at the end of every interval until the `Beat` is dropped or
+// nobody listens anymore. The intervals are measured from the start, so the
+// time spent sending does not make the pulses drift.
+
+fn monitor_beats(repr: &Repr, interval: Duration, mut pulses: MultiSend<Pulse>) {
+  let mut deadline = Instant::now() + interval;
+  let mut missed = 0;
+  loop {
+    let mut st = repr.state.lock().unwrap();
+    loop {
+      let now = Instant::now();
+      if st.stopped {
+        return;
+      }
+      if now >= deadline {
+        break;
+      }
+      st = repr.cond.wait_timeout(st, deadline - now).unwrap().0;
+    }
+    let pulse = match std::mem::take(&mut st.beats) {
+      0 => {
+        missed += 1;
+        Pulse::Missed { missed }
+      }
+      beats => {
+        missed = 0;
+        Pulse::Alive { beats }
+      }
+    };
+    drop(st);
+    if pulses.try_send(pulse).is_err() {
+      return;
+    }
+    deadline += interval;
+  }
+}
+
+#[test]
+fn test_heartbeat_missed() {
+  use std::thread;
+
+  let (beat, pulses) = heartbeat(Duration::from_millis(20));
+  let start = Instant::now();
+  while start.elapsed() < Duration::from_millis(70) {
+    beat.beat();
+    thread::sleep(Duration::from_millis(5));
+  }
+  // Stop beating without dropping the `Beat`, as a stuck component would.
+  thread::sleep(Duration::from_millis(70));
+  drop(beat);
+
+  let all: Vec<_> = pulses.into_iter().collect();
+  assert!(matches!(all[0], Pulse::Alive { beats } if beats > 0));
+  // The run of misses after the last beat counts up from 1.
+  let last_alive = all.iter().rposition(|p| matches!(p, Pulse::Alive { .. })).unwrap();
+  let missed = &all[last_alive + 1..];
+  assert!(missed.len() >= 2);
+  for (i, p) in missed.iter().enumerate() {
+    assert_eq!(*p, Pulse::Missed { missed: i as u32 + 1 });
+  }
+}
+
+/* A `Registry` collects the health of the components of a program, e.g. for a
+readiness probe. Each component registers under a name and gets a `Reporter`,
+through which it reports itself `Healthy`, `Degraded` or `Failed`, with a
+reason for the latter two. `overall()` is the health of the worst component,
+and `subscribe()` returns a channel of the changes, for logging them or
+alerting on them.
+
+A component is registered as `Healthy`. Dropping its reporter unregisters it,
+so a component that was shut down does not hold the overall health back. As
+with `threads::events`, a subscriber that does not keep up only keeps the
+latest `CHANGE_BACKLOG` changes, and dropping the receiver unsubscribes it. */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+  Healthy,
+  // Working, but not as well as it should, e.g. with a backlog.
+  Degraded(String),
+  // Not working.
+  Failed(String),
+}
+
+impl Health {
+  fn severity(&self) -> u8 {
+    match self {
+      Health::Healthy => 0,
+      Health::Degraded(_) => 1,
+      Health::Failed(_) => 2,
+    }
+  }
+}
+
+// A change of a component's health. `old` is `None` when the component was
+// just registered, `new` is `None` when it was unregistered.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+  pub component: String,
+  pub old: Option<Health>,
+  pub new: Option<Health>,
+}
+
+// How many unread changes a subscriber keeps.
+
+pub const CHANGE_BACKLOG: usize = 1024;
+
+struct Components {
+  health: BTreeMap<String, Health>,
+  subscribers: Vec<MultiSend<Change>>,
+}
+
+impl Components {
+  // Records the new health of `component` and tells the subscribers, unless it
+  // did not change.
+
+  fn set(&mut self, component: &str, new: Option<Health>) {
+    let old = match &new {
+      Some(health) => self.health.insert(component.to_string(), health.clone()),
+      None => self.health.remove(component),
+    };
+    if old == new {
+      return;
+    }
+    let change = Change { component: component.to_string(), old, new };
+    // Sending only fails once the receiver is gone, so drop those subscribers.
+    self.subscribers.retain_mut(|s| s.try_send(change.clone()).is_ok());
+  }
+}
+
+pub struct Registry {
+  components: Arc<Mutex<Components>>,
+}
+
+pub struct Reporter {
+  name: String,
+  components: Arc<Mutex<Components>>,
+}
+
+impl Default for Registry {
+  fn default() -> Self {
+    Registry::new()
+  }
+}
+
+impl Registry {
+  pub fn new() -> Registry {
+    let components = Components { health: BTreeMap::new(), subscribers: Vec::new() };
+    Registry { components: Arc::new(Mutex::new(components)) }
+  }
+
+  // Registers a component, as `Healthy`. Panics if a component of that name is
+  // registered already.
+
+  pub fn register(&self, name: impl Into<String>) -> Reporter {
+    let name = name.into();
+    let mut components = self.components.lock().unwrap();
+    assert!(!components.health.contains_key(&name), "health::Registry: {} is already registered", name);
+    components.set(&name, Some(Health::Healthy));
+    Reporter { name, components: self.components.clone() }
+  }
+
+  // The health of the worst component, with the reasons of all components
+  // that are that bad, each prefixed with its name. `Healthy` if there are no
+  // components.
+
+  pub fn overall(&self) -> Health {
+    let components = self.components.lock().unwrap();
+    let Some(worst) = components.health.values().max_by_key(|h| h.severity()) else {
+      return Health::Healthy;
+    };
+    let reasons = components.health.iter().filter_map(|(name, health)| match health {
+      Health::Degraded(reason) | Health::Failed(reason) if health.severity() == worst.severity() => {
+        Some(format!("{}: {}", name, reason))
+      }
+      _ => None,
+    });
+    let reasons = reasons.collect::<Vec<_>>().join("; ");
+    match worst {
+      Health::Healthy => Health::Healthy,
+      Health::Degraded(_) => Health::Degraded(reasons),
+      Health::Failed(_) => Health::Failed(reasons),
+    }
+  }
+
+  // The health of every component, by name.
+
+  pub fn components(&self) -> Vec<(String, Health)> {
+    let components = self.components.lock().unwrap();
+    components.health.iter().map(|(name, health)| (name.clone(), health.clone())).collect()
+  }
+
+  // Subscribes to the changes made from now on.
+
+  pub fn subscribe(&self) -> MultiRecv<Change> {
+    let (s, r) = new_bounded_multi_chan_with_policy(CHANGE_BACKLOG, OverflowPolicy::DropOldest);
+    self.components.lock().unwrap().subscribers.push(s);
+    r
+  }
+}
+
+impl Reporter {
+  pub fn set(&self, health: Health) {
+    self.components.lock().unwrap().set(&self.name, Some(health));
+  }
+
+  pub fn healthy(&self) {
+    self.set(Health::Healthy)
+  }
+
+  pub fn degraded(&self, reason: impl Into<String>) {
+    self.set(Health::Degraded(reason.into()))
+  }
+
+  pub fn failed(&self, reason: impl Into<String>) {
+    self.set(Health::Failed(reason.into()))
+  }
+}
+
+impl Drop for Reporter {
+  fn drop(&mut self) {
+    self.components.lock().unwrap_or_else(|e| e.into_inner()).set(&self.name, None);
+  }
+}
+
+#[test]
+fn test_registry_overall() {
+  let registry = Registry::new();
+  assert_eq!(registry.overall(), Health::Healthy);
+  let db = registry.register("db");
+  let cache = registry.register("cache");
+  let queue = registry.register("queue");
+  assert_eq!(registry.overall(), Health::Healthy);
+
+  cache.degraded("cold");
+  queue.degraded("backlog");
+  assert_eq!(registry.overall(), Health::Degraded("cache: cold; queue: backlog".to_string()));
+  db.failed("unreachable");
+  assert_eq!(registry.overall(), Health::Failed("db: unreachable".to_string()));
+
+  // A component that is gone no longer counts.
+  drop(db);
+  assert_eq!(registry.overall(), Health::Degraded("cache: cold; queue: backlog".to_string()));
+  assert_eq!(registry.components().len(), 2);
+}
+
+#[test]
+fn test_registry_changes() {
+  let registry = Registry::new();
+  let mut changes = registry.subscribe();
+  let worker = registry.register("worker");
+  worker.degraded("slow");
+  // Reporting the same health again is not a change.
+  worker.degraded("slow");
+  worker.healthy();
+  drop(worker);
+
+  let change = |old: Option<Health>, new: Option<Health>| Change { component: "worker".to_string(), old, new };
+  let slow = Health::Degraded("slow".to_string());
+  assert_eq!(changes.try_iter().collect::<Vec<_>>(), vec![
+    change(None, Some(Health::Healthy)),
+    change(Some(Health::Healthy), Some(slow.clone())),
+    change(Some(slow), Some(Health::Healthy)),
+    change(Some(Health::Healthy), None),
+  ]);
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::RecvTimeoutError;
+use crate::oneshot::{new_chan, Recv};
+use crate::race::first_of;
+use crate::sync::CancelToken;
+use crate::threads;
+
+/* Hedged requests.
+
+Most calls to a service answer quickly, but a few take much longer, and those
+few dominate the tail latency. Hedging cuts them short: `run` starts the task,
+and if it has not answered after `delay`, starts a second copy of it and takes
+whichever answers first. Picking `delay` around the usual latency keeps the
+extra work small, as only the slow calls get a second copy.
+
+Each attempt runs on a thread of its own and sends its result on a one-shot
+channel, as in `race::first_ok`. Once there is a result, the shared
+`CancelToken` is cancelled so the attempt that lost can stop early; `run` does
+not wait for it. */
+
+// Runs `task`, and a second copy of it if the first has not answered after
+// `delay`, and returns the first result. An attempt that panics before
+// `delay` is replaced by the second copy right away. Returns `None` if both
+// attempts panic.
+
+pub fn run<T, F>(task: F, delay: Duration) -> Option<T>
+where
+  T: Send + 'static,
+  F: Fn(CancelToken) -> T + Send + Sync + 'static,
+{
+  let task = Arc::new(task);
+  let token = CancelToken::new();
+  let start = |i: usize| -> Recv<T> {
+    let (s, r) = new_chan();
+    let (task, token) = (task.clone(), token.clone());
+    threads::spawn(format!("hedge-{}", i), move || {
+      // The other attempt may have won already; then nobody wants the result.
+      let _ = s.send(task(token));
+    });
+    r
+  };
+
+  let first = start(0);
+  let result = match first.recv_timeout(delay) {
+    Ok(value) => Some(value),
+    Err(RecvTimeoutError::Disconnected) => start(1).recv().ok(),
+    Err(RecvTimeoutError::Timeout) => first_of(vec![first, start(1)]).map(|(_, value)| value),
+  };
+  token.cancel();
+  result
+}
+
+#[test]
+fn test_hedge_fast_task_runs_once() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  let started = Arc::new(AtomicUsize::new(0));
+  let s = started.clone();
+  let value = run(move |_| s.fetch_add(1, Ordering::SeqCst), Duration::from_secs(10));
+  assert_eq!(value, Some(0));
+  assert_eq!(started.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_hedge_slow_task_is_duplicated() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Mutex;
+  use std::time::Instant;
+
+  // The first attempt hangs until it is cancelled, the second answers at once.
+  let started = Arc::new(AtomicUsize::new(0));
+  let (cancelled, saw_cancel) = new_chan::<()>();
+  let cancelled = Mutex::new(Some(cancelled));
+  let s = started.clone();
+  let begin = Instant::now();
+  let value = run(move |token| {
+    let attempt = s.fetch_add(1, Ordering::SeqCst);
+    if attempt == 0 {
+      token.wait_timeout(Duration::from_secs(10));
+      cancelled.lock().unwrap().take().unwrap().send(()).unwrap();
+    }
+    attempt
+  }, Duration::from_millis(20));
+  assert_eq!(value, Some(1));
+  assert!(begin.elapsed() < Duration::from_secs(5));
+  // The losing attempt is told to stop.
+  assert_eq!(saw_cancel.recv_timeout(Duration::from_secs(5)), Ok(()));
+
+  // A first attempt that panics is replaced right away, not after `delay`.
+  let started = AtomicUsize::new(0);
+  let begin = Instant::now();
+  let value = run(move |_| {
+    assert_ne!(started.fetch_add(1, Ordering::SeqCst), 0, "hedge test panic");
+    5
+  }, Duration::from_secs(10));
+  assert_eq!(value, Some(5));
+  assert!(begin.elapsed() < Duration::from_secs(5));
+  assert_eq!(run(|_| -> u32 { panic!("hedge test panic") }, Duration::from_millis(1)), None);
+}
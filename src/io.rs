@@ -0,0 +1,221 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::background;
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::threads;
+
+/* Line channels for standard input and output.
+
+With these adapters a small command-line tool can be written entirely with
+channels: `stdin_lines()` yields the lines of standard input on a multi-shot
+receiver, and `stdout_sink()` returns a sender whose messages are written to
+standard output, one per line. Each adapter does its I/O on a thread of its
+own, so a slow terminal or pipe does not hold up the rest of the program.
+
+The standard-stream adapters run as `background` threads, so call
+`background::shutdown` before exiting to make sure all output is written. For
+other readers and writers, `lines` and `sink` return the join handle instead.
+
+`tail` follows a file that other programs append to, such as a log, like
+`tail -F`. The file has no way to announce new lines, so its thread checks
+for them every `TAIL_POLL`. It also notices when the file is rotated, that is,
+moved away and replaced by a new one, or truncated in place, and then carries
+on from the start of the new contents. */
+
+// Reads lines from `reader` and sends them, without their line endings, until
+// the end of the input, a read error, or the receiver is gone.
+
+fn pump_lines(reader: impl BufRead, mut s: MultiSend<String>) -> io::Result<()> {
+  for line in reader.lines() {
+    s = match s.send(line?) {
+      Ok(s) => s,
+      Err(_) => break,
+    };
+  }
+  Ok(())
+}
+
+// Writes every received message to `writer` as a line, flushing after each one
+// so that the output appears as soon as it is sent.
+
+fn pump_sink<W: Write>(r: MultiRecv<String>, mut writer: W) -> io::Result<W> {
+  for line in r {
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+  }
+  Ok(writer)
+}
+
+// The lines of standard input. The stream ends at the end of the input or at
+// the first read error.
+
+pub fn stdin_lines() -> MultiRecv<String> {
+  let (s, r) = new_multi_chan();
+  background::spawn("io-stdin", move || {
+    let _ = pump_lines(io::stdin().lock(), s);
+  });
+  r
+}
+
+// A sender whose messages are written to standard output, one per line. Close
+// the sender, then call `background::shutdown`, to wait for the output.
+
+pub fn stdout_sink() -> MultiSend<String> {
+  let (s, r) = new_multi_chan();
+  background::spawn("io-stdout", move || {
+    let _ = pump_sink(r, io::stdout().lock());
+  });
+  s
+}
+
+// The lines of `reader`, read on a new thread. The handle reports a read error.
+
+pub fn lines<R: BufRead + Send + 'static>(reader: R) -> (MultiRecv<String>, JoinHandle<io::Result<()>>) {
+  let (s, r) = new_multi_chan();
+  (r, threads::spawn("io-lines".to_string(), move || pump_lines(reader, s)))
+}
+
+// A sender whose messages are written to `writer`, one per line, on a new
+// thread. Once the sender is closed, the handle returns the writer, or the
+// first write error.
+
+pub fn sink<W: Write + Send + 'static>(writer: W) -> (MultiSend<String>, JoinHandle<io::Result<W>>) {
+  let (s, r) = new_multi_chan();
+  (s, threads::spawn("io-sink".to_string(), move || pump_sink(r, writer)))
+}
+
+// How often `tail` checks the file for new lines.
+
+pub const TAIL_POLL: Duration = Duration::from_millis(50);
+
+// The lines of the file at `path`, starting with the ones already in it and
+// then the ones appended later, without their line endings. A line is only
+// sent once its line ending has been written. If the file does not exist yet,
+// the lines are sent once it is created. The stream ends at the first read
+// error, or once the receiver is gone.
+
+pub fn tail(path: impl AsRef<Path>) -> MultiRecv<String> {
+  let path = path.as_ref().to_path_buf();
+  let (s, r) = new_multi_chan();
+  threads::spawn("io-tail".to_string(), move || follow(&path, s));
+  r
+}
+
+fn follow(path: &Path, mut s: MultiSend<String>) {
+  // The open file and how far it has been read.
+  let mut open: Option<(BufReader<File>, u64)> = None;
+  let mut line = String::new();
+  loop {
+    if open.is_none() {
+      open = File::open(path).ok().map(|f| (BufReader::new(f), 0));
+      line.clear();
+    }
+    if let Some((reader, pos)) = &mut open {
+      // Checked before reading, so that what was written to the old file before
+      // it was replaced is still read.
+      let rotated = replaced(path, reader.get_ref(), *pos);
+      loop {
+        match reader.read_line(&mut line) {
+          Ok(0) => break,
+          Ok(n) => {
+            *pos += n as u64;
+            if line.ends_with('\n') {
+              line.pop();
+              if line.ends_with('\r') {
+                line.pop();
+              }
+              s = match s.send(std::mem::take(&mut line)) {
+                Ok(s) => s,
+                Err(_) => return,
+              };
+            }
+          }
+          Err(_) => return,
+        }
+      }
+      if rotated {
+        open = None;
+        continue;
+      }
+    }
+    if s.is_closed() {
+      return;
+    }
+    thread::sleep(TAIL_POLL);
+  }
+}
+
+// Whether the file at `path` is no longer `file`, or has been truncated to
+// less than what has been read. While nothing is at `path`, the file was moved
+// away and its replacement has not been created yet.
+
+fn replaced(path: &Path, file: &File, pos: u64) -> bool {
+  match (fs::metadata(path), file.metadata()) {
+    (Ok(current), Ok(open)) => current.len() < pos || !same_file(&current, &open),
+    _ => false,
+  }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+  use std::os::unix::fs::MetadataExt;
+
+  (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+// Without inode numbers, only truncation is detected.
+
+#[cfg(not(unix))]
+fn same_file(_: &Metadata, _: &Metadata) -> bool {
+  true
+}
+
+#[test]
+fn test_lines_to_sink() {
+  let (lines, reader) = lines(io::Cursor::new("one\r\ntwo\nthree"));
+  let (mut out, writer) = sink(Vec::new());
+  for line in lines {
+    out = out.send(line.to_uppercase()).unwrap();
+  }
+  out.close();
+  reader.join().unwrap().unwrap();
+  assert_eq!(writer.join().unwrap().unwrap(), b"ONE\nTWO\nTHREE\n");
+}
+
+#[test]
+fn test_tail_follows_appends_and_rotation() {
+  use std::fs::OpenOptions;
+
+  let path = std::env::temp_dir().join(format!("test_cargo-tail-{}.log", std::process::id()));
+  let rotated = path.with_extension("log.1");
+  fs::write(&path, "one\ntw").unwrap();
+  let append = |text: &str| {
+    let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+    f.write_all(text.as_bytes()).unwrap();
+  };
+
+  let mut lines = tail(&path).into_iter();
+  assert_eq!(lines.next().as_deref(), Some("one"));
+  // The second line is only complete once its line ending is appended.
+  append("o\r\nthree\n");
+  assert_eq!(lines.next().as_deref(), Some("two"));
+  assert_eq!(lines.next().as_deref(), Some("three"));
+
+  fs::rename(&path, &rotated).unwrap();
+  fs::write(&path, "four\n").unwrap();
+  assert_eq!(lines.next().as_deref(), Some("four"));
+
+  // Truncated in place.
+  fs::write(&path, "").unwrap();
+  thread::sleep(TAIL_POLL * 3);
+  append("five\n");
+  assert_eq!(lines.next().as_deref(), Some("five"));
+
+  drop(lines);
+  fs::remove_file(&path).unwrap();
+  fs::remove_file(&rotated).unwrap();
+}
@@ -0,0 +1,71 @@
+//! Channels and synchronization primitives built on `Mutex`, `Condvar` and
+//! threads from the standard library.
+//!
+//! - [`oneshot`]: a channel on which exactly one message can be sent.
+//! - [`multishot`]: a channel for any number of messages, built from a chain
+//!   of one-shot channels.
+//! - [`mpmc`]: a multi-producer multi-consumer channel where each message goes
+//!   to exactly one of the receivers.
+//! - [`broadcast`]: a channel where every receiver gets every message.
+//! - [`priority`]: a multi-consumer channel that delivers the greatest
+//!   pending message first.
+//! - [`reqres`]: requests that each carry a one-shot channel for the response.
+//! - [`rendezvous`]: a zero-capacity channel where every send waits for a
+//!   receiver to take the message.
+//! - [`watch`]: a channel that holds only the latest value sent.
+//! - [`select`]: waiting on several receivers at once.
+//! - [`sync`]: small synchronization primitives (`OnceFlag`, `Gate`,
+//!   `CancelToken`, `Versioned`).
+//!
+//! The one-shot, multi-shot and rendezvous channel types are re-exported at
+//! the crate root.
+
+pub mod background;
+pub mod batcher;
+pub mod broadcast;
+pub mod cache;
+pub mod combine;
+pub mod duplex;
+pub mod errors;
+pub mod fsm;
+pub mod health;
+pub mod hedge;
+pub mod io;
+pub mod litmus;
+pub mod log;
+pub mod mpmc;
+pub mod multishot;
+pub mod nursery;
+pub mod oneshot;
+pub mod parallel;
+pub mod payload;
+pub mod pipeline;
+pub mod priority;
+pub mod process;
+pub mod race;
+pub mod rate;
+pub mod rendezvous;
+pub mod reqres;
+mod rng;
+pub mod select;
+pub mod shed;
+#[cfg(unix)]
+pub mod signals;
+pub mod singleflight;
+pub mod state;
+pub mod swap;
+pub mod sync;
+pub mod testing;
+pub mod threads;
+pub mod time;
+pub mod tune;
+pub mod txn;
+pub mod watch;
+pub mod wfq;
+
+pub use multishot::{
+  new_bounded_multi_chan, new_bounded_multi_chan_with_policy, new_multi_chan, MultiRecv, MultiSend,
+  OverflowPolicy, SendPermit, SharedPermit, SharedSend,
+};
+pub use oneshot::{new_chan, Recv, Send};
+pub use rendezvous::{sync_chan, SyncRecv, SyncSend};
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Barrier, Mutex};
+use std::thread;
+
+use crate::oneshot::{new_chan, Recv, Send};
+use crate::sync::OnceFlag;
+
+/* Litmus tests for the memory model.
+
+A litmus test is a tiny two-thread program whose possible results tell us
+which reorderings the hardware and compiler are allowed to make. We run each
+test many times, with both threads released at the same moment by a
+`Barrier`, and count how often each outcome `(r1, r2)` was observed.
+
+The two classic tests implemented here are:
+
+ - Message passing: thread 1 writes `data = 1` and then `flag = 1`; thread 2
+   reads `flag` and then `data`. With release/acquire (or a mutex), seeing
+   `flag == 1` but `data == 0` is forbidden.
+ - Store buffering: thread 1 writes `x = 1` and reads `y`; thread 2 writes
+   `y = 1` and reads `x`. Only `SeqCst` forbids both threads reading 0.
+
+Message passing also runs over the crate's own primitives: the flag is then a
+one-shot channel, or a `OnceFlag`, and the data a relaxed atomic. Both
+primitives must make the data written before the flag is set visible to a
+thread that sees the flag set.
+
+Observing a forbidden outcome means the primitive is broken; not observing an
+allowed one proves nothing, it may just be rare on this machine. */
+
+pub type Outcome = (usize, usize);
+
+// The outcomes observed over all iterations of one litmus test.
+
+pub struct Report {
+  pub name: String,
+  pub iterations: usize,
+  pub outcomes: BTreeMap<Outcome, usize>,
+}
+
+impl Report {
+  // How many iterations produced `outcome`.
+
+  pub fn observed(&self, outcome: Outcome) -> usize {
+    self.outcomes.get(&outcome).copied().unwrap_or(0)
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} ({} iterations)", self.name, self.iterations)?;
+    for ((r1, r2), n) in &self.outcomes {
+      write!(f, "\n  r1={} r2={}: {}", r1, r2, n)?;
+    }
+    Ok(())
+  }
+}
+
+// Runs `left` and `right` against a fresh state `iterations` times. Both threads
+// wait on a barrier before every iteration so that they race as closely as
+// possible.
+
+pub fn run<S, L, R>(name: &str, iterations: usize, make: impl Fn() -> S, left: L, right: R) -> Report
+where
+  S: Sync,
+  L: Fn(&S) -> usize + Sync,
+  R: Fn(&S) -> usize + Sync,
+{
+  let states: Vec<S> = (0..iterations).map(|_| make()).collect();
+  let barrier = Barrier::new(2);
+  let (r1, r2) = thread::scope(|s| {
+    let h1 = s.spawn(|| run_side(&states, &barrier, &left));
+    let h2 = s.spawn(|| run_side(&states, &barrier, &right));
+    (h1.join().unwrap(), h2.join().unwrap())
+  });
+
+  let mut outcomes = BTreeMap::new();
+  for outcome in r1.into_iter().zip(r2) {
+    *outcomes.entry(outcome).or_insert(0) += 1;
+  }
+  Report { name: name.to_string(), iterations, outcomes }
+}
+
+fn run_side<S>(states: &[S], barrier: &Barrier, side: &impl Fn(&S) -> usize) -> Vec<usize> {
+  states.iter().map(|st| {
+    barrier.wait();
+    side(st)
+  }).collect()
+}
+
+#[derive(Default)]
+struct Pair {
+  a: AtomicUsize,
+  b: AtomicUsize,
+}
+
+// Message passing over atomics. The writer stores `data` relaxed and `flag`
+// with `store`; the reader loads `flag` with `load` and then `data` relaxed.
+// The reported outcome is `(flag, data)` as seen by the reader. Panics if
+// `store` is not valid for a store (`Acquire`, `AcqRel`) or `load` is not valid
+// for a load (`Release`, `AcqRel`).
+
+pub fn message_passing(iterations: usize, store: Ordering, load: Ordering) -> Report {
+  assert!(!matches!(store, Ordering::Acquire | Ordering::AcqRel), "message_passing: {:?} is not a store ordering", store);
+  assert!(!matches!(load, Ordering::Release | Ordering::AcqRel), "message_passing: {:?} is not a load ordering", load);
+  let name = format!("MP store={:?} load={:?}", store, load);
+  let report = run(&name, iterations, Pair::default,
+    |p: &Pair| {
+      p.a.store(1, Ordering::Relaxed);
+      p.b.store(1, store);
+      0
+    },
+    |p: &Pair| {
+      let flag = p.b.load(load);
+      let data = p.a.load(Ordering::Relaxed);
+      flag * 2 + data
+    });
+  decode_reader(report)
+}
+
+// Message passing where both variables are protected by the same `Mutex`, as
+// in the `Repr<T>` of the one-shot channel.
+
+pub fn mutex_message_passing(iterations: usize) -> Report {
+  let report = run("MP mutex", iterations, || Mutex::new((0, 0)),
+    |m: &Mutex<(usize, usize)>| {
+      m.lock().unwrap().1 = 1;
+      m.lock().unwrap().0 = 1;
+      0
+    },
+    |m: &Mutex<(usize, usize)>| {
+      let flag = m.lock().unwrap().0;
+      let data = m.lock().unwrap().1;
+      flag * 2 + data
+    });
+  decode_reader(report)
+}
+
+// Message passing where the flag is a one-shot channel of the crate: the writer
+// sends a message after storing `data`, and the reader checks with `try_recv`
+// whether it has arrived before loading `data`.
+
+pub fn channel_message_passing(iterations: usize) -> Report {
+  struct Chan {
+    data: AtomicUsize,
+    send: Mutex<Option<Send<()>>>,
+    recv: Recv<()>,
+  }
+
+  let make = || {
+    let (s, r) = new_chan();
+    Chan { data: AtomicUsize::new(0), send: Mutex::new(Some(s)), recv: r }
+  };
+  let report = run("MP oneshot", iterations, make,
+    |c: &Chan| {
+      c.data.store(1, Ordering::Relaxed);
+      let s = c.send.lock().unwrap().take().unwrap();
+      s.send(()).unwrap();
+      0
+    },
+    |c: &Chan| {
+      let flag = c.recv.try_recv().is_ok() as usize;
+      let data = c.data.load(Ordering::Relaxed);
+      flag * 2 + data
+    });
+  decode_reader(report)
+}
+
+// Message passing where the flag is a `OnceFlag`: the writer stores `data` in
+// its initializer, and the reader checks `is_completed` before loading `data`.
+
+pub fn once_flag_message_passing(iterations: usize) -> Report {
+  let report = run("MP OnceFlag", iterations, || (OnceFlag::new(), AtomicUsize::new(0)),
+    |(flag, data): &(OnceFlag, AtomicUsize)| {
+      flag.call_once(|| data.store(1, Ordering::Relaxed));
+      0
+    },
+    |(flag, data): &(OnceFlag, AtomicUsize)| {
+      let flag = flag.is_completed() as usize;
+      let data = data.load(Ordering::Relaxed);
+      flag * 2 + data
+    });
+  decode_reader(report)
+}
+
+// The message passing tests only have an interesting result on the reader
+// side, encoded as `flag * 2 + data`. Turn that back into `(flag, data)`.
+
+fn decode_reader(report: Report) -> Report {
+  let mut outcomes = BTreeMap::new();
+  for ((_, r), n) in report.outcomes {
+    *outcomes.entry((r / 2, r % 2)).or_insert(0) += n;
+  }
+  Report { outcomes, ..report }
+}
+
+// Store buffering with the given ordering used for all stores and loads.
+// `ordering` must be valid for both, i.e. `Relaxed` or `SeqCst`; panics
+// otherwise.
+
+pub fn store_buffering(iterations: usize, ordering: Ordering) -> Report {
+  assert!(matches!(ordering, Ordering::Relaxed | Ordering::SeqCst), "store_buffering: {:?} is not valid for both stores and loads", ordering);
+  let name = format!("SB {:?}", ordering);
+  run(&name, iterations, Pair::default,
+    |p: &Pair| {
+      p.a.store(1, ordering);
+      p.b.load(ordering)
+    },
+    |p: &Pair| {
+      p.b.store(1, ordering);
+      p.a.load(ordering)
+    })
+}
+
+#[test]
+fn test_litmus_message_passing() {
+  let report = message_passing(2000, Ordering::Release, Ordering::Acquire);
+  assert_eq!(report.iterations, 2000);
+  assert_eq!(report.outcomes.values().sum::<usize>(), 2000);
+  assert_eq!(report.observed((1, 0)), 0);
+}
+
+#[test]
+fn test_litmus_mutex_message_passing() {
+  let report = mutex_message_passing(2000);
+  assert_eq!(report.observed((1, 0)), 0);
+}
+
+#[test]
+fn test_litmus_store_buffering() {
+  let report = store_buffering(2000, Ordering::SeqCst);
+  assert_eq!(report.observed((0, 0)), 0);
+}
+
+#[test]
+fn test_litmus_crate_primitives() {
+  assert_eq!(channel_message_passing(2000).observed((1, 0)), 0);
+  assert_eq!(once_flag_message_passing(2000).observed((1, 0)), 0);
+}
+
+#[test]
+#[should_panic(expected = "not a store ordering")]
+fn test_litmus_invalid_ordering() {
+  message_passing(1, Ordering::Acquire, Ordering::Acquire);
+}
@@ -0,0 +1,232 @@
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/* An append-only log with a single writer and any number of lock-free readers.
+
+The log is stored as a list of chunks that are allocated as the log grows and
+never moved or freed until the log itself is dropped. Chunk `k` holds
+`BASE << k` elements, so the number of chunks stays small. The writer fills in
+a slot and then publishes it by bumping `len` with `Release`; readers load
+`len` with `Acquire` and may then read every slot below it. Because published
+slots are never written again, readers can hand out plain `&T` references.
+
+This makes it cheap to share an event history with consumers that join late:
+they get a `SharedLog` handle and iterate from the start, without the writer
+having to clone anything per subscriber. */
+
+const BASE: usize = 32;
+const CHUNKS: usize = usize::BITS as usize;
+
+struct Inner<T> {
+  len: AtomicUsize,
+  chunks: [AtomicPtr<MaybeUninit<T>>; CHUNKS],
+  // The log owns `T`s and hands out `&T`s across threads.
+  _marker: PhantomData<T>,
+}
+
+// The chunk index and offset within that chunk of element `i`.
+
+fn locate(i: usize) -> (usize, usize) {
+  let j = i / BASE + 1;
+  let chunk = (usize::BITS - 1 - j.leading_zeros()) as usize;
+  (chunk, i - BASE * ((1 << chunk) - 1))
+}
+
+fn chunk_len(chunk: usize) -> usize {
+  BASE << chunk
+}
+
+impl<T> Inner<T> {
+  // Safety: `i` must be below a `len` loaded with `Acquire`.
+
+  unsafe fn get_unchecked(&self, i: usize) -> &T {
+    let (chunk, offset) = locate(i);
+    let base = self.chunks[chunk].load(Ordering::Acquire);
+    (*base.add(offset)).assume_init_ref()
+  }
+}
+
+impl<T> Drop for Inner<T> {
+  fn drop(&mut self) {
+    let len = *self.len.get_mut();
+    for i in 0..len {
+      let (chunk, offset) = locate(i);
+      let base = *self.chunks[chunk].get_mut();
+      unsafe { (*base.add(offset)).assume_init_drop() };
+    }
+    for (k, chunk) in self.chunks.iter_mut().enumerate() {
+      let base = *chunk.get_mut();
+      if !base.is_null() {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(base, chunk_len(k))) });
+      }
+    }
+  }
+}
+
+// The writing end. There is exactly one per log, which is what makes the
+// unsynchronized slot writes in `push` sound.
+
+pub struct LogWriter<T> {
+  inner: Arc<Inner<T>>,
+}
+
+// A reading handle. Clone it to give another consumer access to the log.
+
+pub struct SharedLog<T> {
+  inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for SharedLog<T> {
+  fn clone(&self) -> Self {
+    SharedLog { inner: Arc::clone(&self.inner) }
+  }
+}
+
+impl<T> SharedLog<T> {
+  pub fn new() -> (LogWriter<T>, SharedLog<T>) {
+    let inner = Arc::new(Inner {
+      len: AtomicUsize::new(0),
+      chunks: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+      _marker: PhantomData,
+    });
+    (LogWriter { inner: inner.clone() }, SharedLog { inner })
+  }
+
+  // The number of elements published so far.
+
+  pub fn len(&self) -> usize {
+    self.inner.len.load(Ordering::Acquire)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn get(&self, i: usize) -> Option<&T> {
+    if i < self.len() {
+      Some(unsafe { self.inner.get_unchecked(i) })
+    } else {
+      None
+    }
+  }
+
+  // Iterates over the elements that were published when `iter` was called.
+
+  pub fn iter(&self) -> Iter<'_, T> {
+    self.iter_from(0)
+  }
+
+  // Like `iter`, but starts at element `start`, so a consumer can pick up
+  // where it left off.
+
+  pub fn iter_from(&self, start: usize) -> Iter<'_, T> {
+    Iter { inner: &self.inner, next: start, end: self.len() }
+  }
+}
+
+impl<T> LogWriter<T> {
+  // Appends `value` and returns its index.
+
+  pub fn push(&mut self, value: T) -> usize {
+    let i = self.inner.len.load(Ordering::Relaxed);
+    let (chunk, offset) = locate(i);
+    let slot = &self.inner.chunks[chunk];
+    let mut base = slot.load(Ordering::Relaxed);
+    if base.is_null() {
+      let mut buf: Vec<MaybeUninit<T>> = Vec::with_capacity(chunk_len(chunk));
+      buf.resize_with(chunk_len(chunk), MaybeUninit::uninit);
+      base = Box::into_raw(buf.into_boxed_slice()).cast::<MaybeUninit<T>>();
+      slot.store(base, Ordering::Release);
+    }
+    unsafe { (*base.add(offset)).write(value) };
+    self.inner.len.store(i + 1, Ordering::Release);
+    i
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.len.load(Ordering::Relaxed)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // A new reading handle for this log.
+
+  pub fn reader(&self) -> SharedLog<T> {
+    SharedLog { inner: self.inner.clone() }
+  }
+}
+
+pub struct Iter<'a, T> {
+  inner: &'a Inner<T>,
+  next: usize,
+  end: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+  type Item = &'a T;
+
+  fn next(&mut self) -> Option<&'a T> {
+    if self.next < self.end {
+      let item = unsafe { self.inner.get_unchecked(self.next) };
+      self.next += 1;
+      Some(item)
+    } else {
+      None
+    }
+  }
+}
+
+#[test]
+fn test_shared_log_locate() {
+  assert_eq!(locate(0), (0, 0));
+  assert_eq!(locate(BASE - 1), (0, BASE - 1));
+  assert_eq!(locate(BASE), (1, 0));
+  assert_eq!(locate(3 * BASE - 1), (1, 2 * BASE - 1));
+  assert_eq!(locate(3 * BASE), (2, 0));
+}
+
+#[test]
+fn test_shared_log_concurrent_readers() {
+  use std::thread;
+
+  let (mut writer, log) = SharedLog::new();
+  let readers: Vec<_> = (0..4).map(|_| {
+    let log = log.clone();
+    thread::spawn(move || {
+      let mut seen = 0;
+      while seen < 10_000 {
+        for v in log.iter_from(seen) {
+          assert_eq!(*v, seen);
+          seen += 1;
+        }
+      }
+    })
+  }).collect();
+  for i in 0..10_000 {
+    assert_eq!(writer.push(i), i);
+  }
+  for r in readers {
+    r.join().unwrap();
+  }
+  assert_eq!(log.get(9_999), Some(&9_999));
+  assert_eq!(log.get(10_000), None);
+}
+
+#[test]
+fn test_shared_log_drops_elements() {
+  let marker = Arc::new(());
+  let (mut writer, log) = SharedLog::new();
+  for _ in 0..100 {
+    writer.push(marker.clone());
+  }
+  assert_eq!(Arc::strong_count(&marker), 101);
+  drop(writer);
+  assert_eq!(log.iter().count(), 100);
+  drop(log);
+  assert_eq!(Arc::strong_count(&marker), 1);
+}
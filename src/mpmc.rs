@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+
+/* Multi-producer multi-consumer channels.
+
+Both ends can be cloned. Every message is delivered to exactly one of the
+receivers, so several worker threads can compete for the messages of one
+queue. This is something the multi-shot channel cannot do: its receiver is
+consumed by every `recv`, so only one thread can hold it.
+
+The representation is a queue behind a mutex, together with the number of
+senders and receivers, so that each side can tell when the other is gone. */
+
+struct Repr<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar,
+}
+
+struct State<T> {
+  queue: VecDeque<T>,
+  senders: usize,
+  receivers: usize,
+}
+
+pub struct Sender<T> {
+  repr: Arc<Repr<T>>,
+}
+
+pub struct Receiver<T> {
+  repr: Arc<Repr<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+  let state = State { queue: VecDeque::new(), senders: 1, receivers: 1 };
+  let repr = Arc::new(Repr { state: Mutex::new(state), cond: Condvar::new() });
+  (Sender { repr: repr.clone() }, Receiver { repr })
+}
+
+impl<T> Sender<T> {
+  // Queues `msg` for one of the receivers. Fails, handing `msg` back, if all
+  // receivers are gone.
+
+  pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+    let mut state = self.repr.state.lock().unwrap();
+    if state.receivers == 0 {
+      return Err(SendError(msg));
+    }
+    state.queue.push_back(msg);
+    self.repr.cond.notify_one();
+    Ok(())
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    self.repr.state.lock().unwrap().senders += 1;
+    Sender { repr: self.repr.clone() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.repr.state.lock().unwrap_or_else(|e| e.into_inner());
+    state.senders -= 1;
+    if state.senders == 0 {
+      // Wake up all receivers so they can see the channel is closed.
+      self.repr.cond.notify_all();
+    }
+  }
+}
+
+impl<T> Receiver<T> {
+  // Blocks until a message is available. Fails once the queue is empty and all
+  // senders are gone.
+
+  pub fn recv(&self) -> Result<T, RecvError> {
+    let state = self.repr.state.lock().unwrap();
+    let mut state = self.repr.cond.wait_while(state, |s| s.queue.is_empty() && s.senders > 0).unwrap();
+    state.queue.pop_front().ok_or(RecvError::Disconnected)
+  }
+
+  pub fn try_recv(&self) -> Result<T, TryRecvError> {
+    let mut state = self.repr.state.lock().unwrap();
+    match state.queue.pop_front() {
+      Some(msg) => Ok(msg),
+      None if state.senders == 0 => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.repr.state.lock().unwrap();
+    loop {
+      if let Some(msg) = state.queue.pop_front() {
+        return Ok(msg);
+      }
+      if state.senders == 0 {
+        return Err(RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(RecvTimeoutError::Timeout);
+      }
+      state = self.repr.cond.wait_timeout(state, deadline - now).unwrap().0;
+    }
+  }
+
+  // Iterates over the messages this receiver gets until the channel is closed.
+
+  pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+    std::iter::from_fn(move || self.recv().ok())
+  }
+}
+
+impl<T> Clone for Receiver<T> {
+  fn clone(&self) -> Self {
+    self.repr.state.lock().unwrap().receivers += 1;
+    Receiver { repr: self.repr.clone() }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.repr.state.lock().unwrap_or_else(|e| e.into_inner());
+    state.receivers -= 1;
+    if state.receivers == 0 {
+      // Nobody will receive the queued messages anymore. Drop them now, but
+      // outside the lock, as their destructors may do anything.
+      let queue = std::mem::take(&mut state.queue);
+      drop(state);
+      drop(queue);
+    }
+  }
+}
+
+#[test]
+fn test_mpmc_each_message_once() {
+  use std::thread;
+
+  let (tx, rx) = channel();
+  let producers: Vec<_> = (0..4).map(|p| {
+    let tx = tx.clone();
+    thread::spawn(move || {
+      for i in 0..250 {
+        tx.send(p * 1000 + i).unwrap();
+      }
+    })
+  }).collect();
+  drop(tx);
+
+  let consumers: Vec<_> = (0..4).map(|_| {
+    let rx = rx.clone();
+    thread::spawn(move || rx.iter().collect::<Vec<_>>())
+  }).collect();
+  drop(rx);
+
+  for p in producers {
+    p.join().unwrap();
+  }
+  let mut all: Vec<_> = consumers.into_iter().flat_map(|c| c.join().unwrap()).collect();
+  all.sort();
+  let expected: Vec<_> = (0..4).flat_map(|p| (0..250).map(move |i| p * 1000 + i)).collect();
+  assert_eq!(all, expected);
+}
+
+#[test]
+fn test_mpmc_disconnect() {
+  let (tx, rx) = channel();
+  tx.send(1).unwrap();
+  drop(tx);
+  assert_eq!(rx.try_recv(), Ok(1));
+  assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+  assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Err(RecvTimeoutError::Disconnected));
+
+  let (tx, rx) = channel();
+  drop(rx);
+  assert_eq!(tx.send(2), Err(SendError(2)));
+}
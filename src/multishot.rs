@@ -0,0 +1,831 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::errors::{MultiSendError, SendError, TryRecvError, TrySendError};
+use crate::oneshot::{new_chan, Recv, Send, Slot};
+use crate::select::{Selectable, Signal};
+
+/* Multi-shot channels built from single-shot channels.
+
+Every message is sent over its own one-shot channel, together with the
+receiver of the one-shot channel for the next message. Sending therefore
+consumes the sender and returns the sender for the next message, and receiving
+consumes the receiver and returns the receiver for the next message.
+
+The stream ends when the sender is closed, either explicitly with `close()` or
+by dropping it. Closing drops the one-shot sender of the next message, so the
+receiver sees that channel disconnect and `recv()` returns `None`.
+
+A bounded channel limits how many messages can be sent but not yet received.
+Each message carries a `Permit` for one unit of capacity, stored in the
+receiver that travels with it. The permit is released when the message is
+received, or when it is dropped unreceived because the receiver went away, and
+a sender that found the channel full is woken up.
+
+Under `OverflowPolicy::DropOldest` the sender also keeps a `Slot` for each
+one-shot channel it sent on and that has not been received yet. To displace
+the oldest message, it moves the second-oldest message (with the receiver that
+came with it) into the channel of the oldest one, which cuts the oldest
+message and its channel out of the chain and frees them right away. */
+
+// These are the representations of the receiver and sender that can be used
+// to send multiple messages.
+
+pub struct MultiRecv<T> {
+  receiver: Recv<(T,MultiRecv<T>)>,
+  // The capacity held by the message that carried this receiver.
+  permit: Option<Permit>,
+}
+pub struct MultiSend<T> {
+  sender: Send<(T,MultiRecv<T>)>,
+  bound: Option<Arc<Bound>>,
+  // The channels sent on and not yet received, oldest first. Only kept under
+  // `OverflowPolicy::DropOldest`.
+  sent: VecDeque<Slot<(T,MultiRecv<T>)>>,
+}
+
+// What a bounded channel does when a message is sent while it is full.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  // Wait until the receiver has made room.
+  #[default]
+  Block,
+  // Accept the new message and discard the oldest unreceived one, which is
+  // freed right away.
+  DropOldest,
+  // Discard the new message.
+  DropNewest,
+  // Refuse the new message without blocking. `send` hands back the message and
+  // the sender in `MultiSendError::Full`, `try_send` in `TrySendError::Full`.
+  Fail,
+}
+
+// The shared capacity of a bounded channel.
+
+pub(crate) struct Bound {
+  policy: OverflowPolicy,
+  load: Mutex<Load>,
+  cond: Condvar,
+}
+
+struct Load {
+  // Can be changed while the channel is in use, see `tune`.
+  capacity: usize,
+  // Messages sent and not yet received or dropped.
+  in_flight: usize,
+  // Since the last `take_stats`: how often a message found the channel full,
+  // and the most messages that were in flight.
+  full: u64,
+  peak: usize,
+  // Signals of the `select_send`s waiting for room.
+  watchers: Vec<Arc<Signal>>,
+}
+
+impl Load {
+  fn is_full(&self) -> bool {
+    self.in_flight >= self.capacity
+  }
+
+  fn claim(&mut self) {
+    self.in_flight += 1;
+    self.peak = self.peak.max(self.in_flight);
+  }
+
+  fn notify_watchers(&self) {
+    for w in &self.watchers {
+      w.notify();
+    }
+  }
+}
+
+// The outcome of asking a bounded channel for room for one message.
+
+enum Reserved {
+  Permit(Permit),
+  // The channel was full: send the message, then displace the oldest one.
+  Displace(Permit),
+  Dropped,
+  Full,
+}
+
+impl Bound {
+  // Claims room for one message, applying the overflow policy if the channel
+  // is full. Only blocks if `block` is set and the policy is `Block`.
+
+  fn reserve(self: &Arc<Bound>, block: bool) -> Reserved {
+    let mut load = self.load.lock().unwrap();
+    if load.is_full() {
+      load.full += 1;
+      match self.policy {
+        OverflowPolicy::Block if block => {
+          load = self.cond.wait_while(load, |l| l.is_full()).unwrap();
+        }
+        OverflowPolicy::Block | OverflowPolicy::Fail => return Reserved::Full,
+        OverflowPolicy::DropNewest => return Reserved::Dropped,
+        OverflowPolicy::DropOldest => {
+          // Over capacity until the oldest message has been displaced.
+          load.claim();
+          return Reserved::Displace(Permit(self.clone()));
+        }
+      }
+    }
+    load.claim();
+    Reserved::Permit(Permit(self.clone()))
+  }
+
+  // Claims room for one message whatever the overflow policy, waiting for it
+  // if `block` is set. Returns `None` if the channel is full and `block` is
+  // not set.
+
+  fn acquire(self: &Arc<Bound>, block: bool) -> Option<Permit> {
+    let mut load = self.load.lock().unwrap();
+    if load.is_full() {
+      load.full += 1;
+      if !block {
+        return None;
+      }
+      load = self.cond.wait_while(load, |l| l.is_full()).unwrap();
+    }
+    load.claim();
+    Some(Permit(self.clone()))
+  }
+
+  pub(crate) fn capacity(&self) -> usize {
+    self.load.lock().unwrap().capacity
+  }
+
+  // Changes the capacity. Messages already in flight stay, even if there are
+  // more of them than the new capacity; senders then wait until enough of them
+  // have been received.
+
+  pub(crate) fn resize(&self, capacity: usize) {
+    assert!(capacity > 0, "resize: capacity must be positive");
+    let mut load = self.load.lock().unwrap();
+    load.capacity = capacity;
+    load.notify_watchers();
+    self.cond.notify_all();
+  }
+
+  // Registers `signal` to be notified when room may have become free, for
+  // `select_send`.
+
+  pub(crate) fn watch(&self, signal: &Arc<Signal>) {
+    self.load.lock().unwrap().watchers.push(signal.clone());
+  }
+
+  pub(crate) fn unwatch(&self, signal: &Arc<Signal>) {
+    self.load.lock().unwrap().watchers.retain(|w| !Arc::ptr_eq(w, signal));
+  }
+
+  // Returns how often a message found the channel full since the last call,
+  // and the most messages that were in flight, and starts counting anew.
+
+  pub(crate) fn take_stats(&self) -> (u64, usize) {
+    let mut load = self.load.lock().unwrap();
+    let stats = (load.full, load.peak);
+    load.full = 0;
+    load.peak = load.in_flight;
+    stats
+  }
+}
+
+// One message's claim on the capacity of a bounded channel. Released when
+// dropped.
+
+struct Permit(Arc<Bound>);
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    let mut load = self.0.load.lock().unwrap_or_else(|e| e.into_inner());
+    load.in_flight -= 1;
+    load.notify_watchers();
+    self.0.cond.notify_one();
+  }
+}
+
+fn chan_with_bound<T>(bound: Option<Arc<Bound>>) -> (MultiSend<T>,MultiRecv<T>) {
+  let (sender, receiver) = new_chan();
+  (MultiSend { sender, bound, sent: VecDeque::new() }, MultiRecv { receiver, permit: None })
+}
+
+// A multi-shot channel is a one-shot channel carrying the first message.
+
+pub fn new_multi_chan<T>() -> (MultiSend<T>,MultiRecv<T>) {
+  chan_with_bound(None)
+}
+
+// A multi-shot channel on which at most `capacity` messages can be in flight.
+// Sending blocks while the channel is full.
+
+pub fn new_bounded_multi_chan<T>(capacity: usize) -> (MultiSend<T>,MultiRecv<T>) {
+  new_bounded_multi_chan_with_policy(capacity, OverflowPolicy::Block)
+}
+
+// Like `new_bounded_multi_chan`, with `policy` deciding what happens to
+// messages sent while the channel is full.
+
+pub fn new_bounded_multi_chan_with_policy<T>(capacity: usize, policy: OverflowPolicy) -> (MultiSend<T>,MultiRecv<T>) {
+  assert!(capacity > 0, "new_bounded_multi_chan: capacity must be positive");
+  let load = Load { capacity, in_flight: 0, full: 0, peak: 0, watchers: Vec::new() };
+  let bound = Bound { policy, load: Mutex::new(load), cond: Condvar::new() };
+  chan_with_bound(Some(Arc::new(bound)))
+}
+
+// Receiving waits for the one-shot channel of the current message, which also
+// carries the receiver for the next message. Returns `None` once the sender
+// has been closed and all messages have been received. Receiving a message
+// releases the capacity it held.
+
+impl<T> MultiRecv<T> {
+  pub fn recv(self) -> Option<(T,MultiRecv<T>)> {
+    let (msg, mut next) = self.receiver.recv_in_place().ok()?;
+    next.permit = None;
+    Some((msg, next))
+  }
+
+  // Takes the next message if it has already been sent, without blocking. On
+  // success the receiver moves on to the next message in place.
+
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let (msg, mut next) = self.receiver.try_recv()?;
+    next.permit = None;
+    *self = next;
+    Ok(msg)
+  }
+
+  // Iterates over the messages that are available right now, stopping at the
+  // first one that has not been sent yet (or when the stream is closed).
+
+  pub fn try_iter(&mut self) -> TryIter<'_, T> {
+    TryIter { receiver: self }
+  }
+}
+
+// Dropping a receiver drops the messages that were sent but not received. Each
+// of them carries the receiver for the next one, so leaving this to the fields
+// would recurse once per message and overflow the stack on a long backlog.
+// Instead, take each message out of its link before the link is dropped, which
+// leaves the link's own drop with nothing to recurse into.
+
+impl<T> Drop for MultiRecv<T> {
+  fn drop(&mut self) {
+    let mut next = self.receiver.try_recv().ok().map(|(_, next)| next);
+    while let Some(r) = next {
+      next = r.receiver.try_recv().ok().map(|(_, next)| next);
+    }
+  }
+}
+
+// Selecting on a multi-shot receiver waits for its next message.
+
+impl<T> Selectable for MultiRecv<T> {
+  fn select_ready(&self) -> bool {
+    self.receiver.select_ready()
+  }
+
+  fn watch(&self, signal: &Arc<Signal>) {
+    self.receiver.watch(signal)
+  }
+
+  fn unwatch(&self, signal: &Arc<Signal>) {
+    self.receiver.unwatch(signal)
+  }
+}
+
+pub struct TryIter<'a, T> {
+  receiver: &'a mut MultiRecv<T>,
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.receiver.try_recv().ok()
+  }
+}
+
+// Iterating over a receiver yields its messages until the sender is closed.
+
+pub struct IntoIter<T> {
+  receiver: Option<MultiRecv<T>>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    let (msg, next) = self.receiver.take()?.recv()?;
+    self.receiver = Some(next);
+    Some(msg)
+  }
+}
+
+impl<T> IntoIterator for MultiRecv<T> {
+  type Item = T;
+  type IntoIter = IntoIter<T>;
+
+  fn into_iter(self) -> IntoIter<T> {
+    IntoIter { receiver: Some(self) }
+  }
+}
+
+// Sending creates the channel for the next message, sends its receiver along
+// with `msg`, and keeps its sender. On a bounded channel, it first applies the
+// overflow policy if the channel is full. If the message cannot be delivered,
+// it is handed back without the receiver that was bundled with it; under
+// `OverflowPolicy::Fail` the sender is handed back as well.
+
+impl<T> MultiSend<T> {
+  pub fn send(self, msg: T) -> Result<MultiSend<T>, MultiSendError<T>> {
+    let mut this = self;
+    match this.send_in_place(msg) {
+      Ok(()) => Ok(this),
+      Err(TrySendError::Full(msg)) => Err(MultiSendError::Full(msg, this)),
+      Err(TrySendError::Disconnected(msg)) => Err(MultiSendError::Disconnected(msg)),
+    }
+  }
+
+  // `send` for a sender that is borrowed, as in `SharedSend`.
+
+  pub(crate) fn send_in_place(&mut self, msg: T) -> Result<(), TrySendError<T>> {
+    self.send_reserved(msg, true)
+  }
+
+  // Like `send`, but never blocks, and keeps the sender in place so it can be
+  // used again after a failure. Returns `TrySendError::Full` if the channel is
+  // full and its policy is `Block` or `Fail`.
+
+  pub fn try_send(&mut self, msg: T) -> Result<(), TrySendError<T>> {
+    self.send_reserved(msg, false)
+  }
+
+  fn send_reserved(&mut self, msg: T, block: bool) -> Result<(), TrySendError<T>> {
+    let reserved = self.bound.as_ref().map(|bound| bound.reserve(block));
+    self.send_with(msg, reserved)
+  }
+
+  // Sends `msg` according to what reserving room for it turned up.
+
+  fn send_with(&mut self, msg: T, reserved: Option<Reserved>) -> Result<(), TrySendError<T>> {
+    match reserved {
+      None => self.push(msg, None),
+      Some(Reserved::Permit(permit)) => self.push(msg, Some(permit)),
+      Some(Reserved::Displace(permit)) => {
+        self.push(msg, Some(permit))?;
+        self.displace_oldest();
+        Ok(())
+      }
+      Some(Reserved::Dropped) => Ok(()),
+      Some(Reserved::Full) => Err(TrySendError::Full(msg)),
+    }
+  }
+
+  // Sends `msg` with the capacity already claimed for it and moves `self` on to
+  // the sender for the next message.
+
+  fn push(&mut self, msg: T, permit: Option<Permit>) -> Result<(), TrySendError<T>> {
+    let (sender, receiver) = new_chan();
+    let this = std::mem::replace(&mut self.sender, sender);
+    let slot = self.bound.as_ref().is_some_and(|b| b.policy == OverflowPolicy::DropOldest).then(|| this.slot());
+    match this.send((msg, MultiRecv { receiver, permit })) {
+      Ok(()) => {
+        if let Some(slot) = slot {
+          // Forget the channels the receiver has already taken the message from.
+          while self.sent.front().is_some_and(Slot::is_empty) {
+            self.sent.pop_front();
+          }
+          self.sent.push_back(slot);
+        }
+        Ok(())
+      }
+      Err(SendError((msg, _))) => Err(TrySendError::Disconnected(msg)),
+    }
+  }
+
+  // Drops the oldest message that has not been received, by moving the next
+  // one into its channel. The message that is cut out carries the permit of
+  // the displaced message, so this also gives its capacity back. Does nothing
+  // if the receiver has caught up in the meantime.
+
+  fn displace_oldest(&mut self) {
+    while self.sent.len() >= 2 {
+      match self.sent[0].replace_from(&self.sent[1]) {
+        Some(displaced) => {
+          self.sent.remove(1);
+          drop(displaced);
+          return;
+        }
+        // The oldest one has been received after all.
+        None => {
+          self.sent.pop_front();
+        }
+      }
+    }
+  }
+
+  // The capacity of a bounded channel, for `tune`.
+
+  pub(crate) fn bound(&self) -> Option<&Arc<Bound>> {
+    self.bound.as_ref()
+  }
+
+  // Whether the receiver is gone, so that sending would fail. Lets a sender
+  // that only sends now and then notice this without sending.
+
+  pub(crate) fn is_closed(&self) -> bool {
+    self.sender.is_closed()
+  }
+
+  // Ends the stream. Dropping the sender has the same effect; this method
+  // makes the intent explicit at the call site.
+
+  pub fn close(self) {}
+
+  // Turns this sender into one that can be cloned, so that several threads can
+  // send to the same receiver.
+
+  pub fn into_shared(self) -> SharedSend<T> {
+    SharedSend { bound: self.bound.clone(), sender: Arc::new(Mutex::new(self)) }
+  }
+}
+
+/* Reserving capacity.
+
+`reserve` claims room for one message on a bounded channel before the message
+exists, e.g. before doing the work of building it, and returns a permit for
+it. Sending with the permit uses the room it holds, so it never blocks and
+never runs into the overflow policy; it only fails if the receiver is gone.
+Dropping the permit unused gives the room back.
+
+`reserve` waits for room whatever the overflow policy; `try_reserve` returns
+`None` instead if the channel is full. On an unbounded channel both succeed
+right away. */
+
+pub struct SendPermit<'a, T> {
+  sender: &'a mut MultiSend<T>,
+  permit: Option<Permit>,
+}
+
+impl<T> MultiSend<T> {
+  pub fn reserve(&mut self) -> SendPermit<'_, T> {
+    let permit = self.bound.as_ref().and_then(|bound| bound.acquire(true));
+    SendPermit { sender: self, permit }
+  }
+
+  pub fn try_reserve(&mut self) -> Option<SendPermit<'_, T>> {
+    let permit = match &self.bound {
+      Some(bound) => Some(bound.acquire(false)?),
+      None => None,
+    };
+    Some(SendPermit { sender: self, permit })
+  }
+}
+
+impl<T> SendPermit<'_, T> {
+  pub fn send(self, msg: T) -> Result<(), SendError<T>> {
+    self.sender.push(msg, self.permit).map_err(|e| SendError(e.into_inner()))
+  }
+}
+
+/* Shared senders.
+
+A `MultiSend` moves on to a new one-shot channel with every message, so it
+cannot be cloned. A `SharedSend` puts it behind a mutex instead: every clone
+sends through the same `MultiSend`, one message at a time, so the receiver sees
+the messages of each sender in the order they were sent. The `MultiSend` is
+dropped with the last clone, which closes the stream.
+
+On a full bounded channel with `OverflowPolicy::Block`, `send` waits for room
+before it takes the mutex, so a clone that holds a `SharedPermit` can still
+send while others wait. With `OverflowPolicy::Fail`, `send` returns
+`TrySendError::Full` instead, like `try_send`. */
+
+pub struct SharedSend<T> {
+  sender: Arc<Mutex<MultiSend<T>>>,
+  // The capacity of the channel, to wait for room without the mutex.
+  bound: Option<Arc<Bound>>,
+}
+
+pub struct SharedPermit<T> {
+  sender: SharedSend<T>,
+  permit: Option<Permit>,
+}
+
+impl<T> SharedSend<T> {
+  pub fn send(&self, msg: T) -> Result<(), TrySendError<T>> {
+    let reserved = self.bound.as_ref().map(|bound| bound.reserve(true));
+    self.sender.lock().unwrap().send_with(msg, reserved)
+  }
+
+  pub fn try_send(&self, msg: T) -> Result<(), TrySendError<T>> {
+    self.sender.lock().unwrap().try_send(msg)
+  }
+
+  // Like `MultiSend::reserve`.
+
+  pub fn reserve(&self) -> SharedPermit<T> {
+    let permit = self.bound.as_ref().and_then(|bound| bound.acquire(true));
+    SharedPermit { sender: self.clone(), permit }
+  }
+
+  pub fn try_reserve(&self) -> Option<SharedPermit<T>> {
+    let permit = match &self.bound {
+      Some(bound) => Some(bound.acquire(false)?),
+      None => None,
+    };
+    Some(SharedPermit { sender: self.clone(), permit })
+  }
+}
+
+impl<T> SharedPermit<T> {
+  pub fn send(self, msg: T) -> Result<(), SendError<T>> {
+    let mut sender = self.sender.sender.lock().unwrap();
+    sender.push(msg, self.permit).map_err(|e| SendError(e.into_inner()))
+  }
+}
+
+impl<T> Clone for SharedSend<T> {
+  fn clone(&self) -> Self {
+    SharedSend { sender: self.sender.clone(), bound: self.bound.clone() }
+  }
+}
+
+#[test]
+fn test_multi_chan_in_order() {
+  use std::thread;
+
+  let (mut s, mut r) = new_multi_chan();
+  let h = thread::spawn(move || {
+    for i in 0..10 {
+      s = s.send(i).unwrap();
+    }
+  });
+  for i in 0..10 {
+    let (msg, next) = r.recv().unwrap();
+    assert_eq!(msg, i);
+    r = next;
+  }
+  h.join().unwrap();
+}
+
+#[test]
+fn test_multi_send_after_receiver_dropped() {
+  let (s, r) = new_multi_chan();
+  let s = s.send(1).unwrap();
+  drop(r);
+  match s.send(2) {
+    Err(MultiSendError::Disconnected(msg)) => assert_eq!(msg, 2),
+    _ => panic!("send to a dropped receiver succeeded"),
+  }
+}
+
+#[test]
+fn test_multi_chan_close() {
+  let (s, r) = new_multi_chan();
+  s.send("a").unwrap().send("b").unwrap().close();
+  let (a, r) = r.recv().unwrap();
+  let (b, r) = r.recv().unwrap();
+  assert_eq!((a, b), ("a", "b"));
+  assert!(r.recv().is_none());
+
+  // Dropping the sender closes the stream as well.
+  let (s, r) = new_multi_chan::<i32>();
+  drop(s);
+  assert!(r.recv().is_none());
+}
+
+#[test]
+fn test_multi_chan_iter() {
+  use std::thread;
+
+  let (mut s, r) = new_multi_chan();
+  let h = thread::spawn(move || {
+    for i in 0..5 {
+      s = s.send(i).unwrap();
+    }
+  });
+  let mut received = vec![];
+  for msg in r {
+    received.push(msg);
+  }
+  assert_eq!(received, vec![0, 1, 2, 3, 4]);
+  h.join().unwrap();
+}
+
+#[test]
+fn test_multi_chan_try_iter() {
+  let (s, mut r) = new_multi_chan();
+  assert_eq!(r.try_iter().count(), 0);
+  let s = s.send(1).unwrap().send(2).unwrap();
+  assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+  assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+  s.send(3).unwrap().close();
+  assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![3]);
+  assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_bounded_multi_chan_blocks_when_full() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  let (mut s, mut r) = new_bounded_multi_chan(2);
+  let sent = Arc::new(AtomicUsize::new(0));
+  let sent2 = sent.clone();
+  let h = thread::spawn(move || {
+    for i in 0..5 {
+      s = s.send(i).unwrap();
+      sent2.fetch_add(1, Ordering::SeqCst);
+    }
+  });
+
+  thread::sleep(Duration::from_millis(50));
+  assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+  for i in 0..5 {
+    let (msg, next) = r.recv().unwrap();
+    assert_eq!(msg, i);
+    r = next;
+  }
+  h.join().unwrap();
+  assert_eq!(sent.load(Ordering::SeqCst), 5);
+}
+
+#[test]
+fn test_bounded_multi_chan_receiver_dropped() {
+  use std::thread;
+  use std::time::Duration;
+
+  let (s, r) = new_bounded_multi_chan(1);
+  let s = s.send(1).unwrap();
+  let h = thread::spawn(move || s.send(2).map(|_| ()).map_err(MultiSendError::into_inner));
+  thread::sleep(Duration::from_millis(20));
+  // Dropping the receiver releases the capacity held by the unreceived
+  // message, so the blocked sender wakes up and sees the channel is gone.
+  drop(r);
+  assert_eq!(h.join().unwrap(), Err(2));
+}
+
+#[test]
+fn test_overflow_policies() {
+  let collect = |r: MultiRecv<i32>| r.into_iter().collect::<Vec<_>>();
+
+  let (mut s, r) = new_bounded_multi_chan_with_policy(2, OverflowPolicy::DropNewest);
+  for i in 0..5 {
+    s = s.send(i).unwrap();
+  }
+  drop(s);
+  assert_eq!(collect(r), vec![0, 1]);
+
+  let (mut s, r) = new_bounded_multi_chan_with_policy(2, OverflowPolicy::DropOldest);
+  for i in 0..5 {
+    s = s.send(i).unwrap();
+  }
+  drop(s);
+  assert_eq!(collect(r), vec![3, 4]);
+
+  let (s, mut r) = new_bounded_multi_chan_with_policy(2, OverflowPolicy::Fail);
+  // `send` does not block on a full channel but hands back the sender.
+  let s = s.send(0).unwrap().send(1).unwrap();
+  let mut s = match s.send(2) {
+    Err(MultiSendError::Full(2, s)) => s,
+    _ => panic!("send on a full channel did not fail"),
+  };
+  assert_eq!(r.try_recv(), Ok(0));
+  assert_eq!(r.try_recv(), Ok(1));
+  assert_eq!(s.try_send(0), Ok(()));
+  assert_eq!(s.try_send(1), Ok(()));
+  assert_eq!(s.try_send(2), Err(TrySendError::Full(2)));
+  assert_eq!(r.try_recv(), Ok(0));
+  assert_eq!(s.try_send(3), Ok(()));
+  drop(s);
+  assert_eq!(collect(r), vec![1, 3]);
+}
+
+#[test]
+fn test_try_send_disconnected() {
+  let (mut s, r) = new_multi_chan();
+  assert_eq!(s.try_send(1), Ok(()));
+  drop(r);
+  assert_eq!(s.try_send(2), Err(TrySendError::Disconnected(2)));
+}
+
+#[test]
+fn test_shared_send() {
+  use std::thread;
+
+  let (s, r) = new_multi_chan();
+  let s = s.into_shared();
+  let handles: Vec<_> = (0..4).map(|t| {
+    let s = s.clone();
+    thread::spawn(move || {
+      for i in 0..10 {
+        s.send((t, i)).unwrap();
+      }
+    })
+  }).collect();
+  drop(s);
+  for h in handles {
+    h.join().unwrap();
+  }
+
+  // The stream ends once every clone is gone.
+  let received: Vec<_> = r.into_iter().collect();
+  assert_eq!(received.len(), 40);
+  for t in 0..4 {
+    let from_t: Vec<_> = received.iter().filter(|m| m.0 == t).map(|m| m.1).collect();
+    assert_eq!(from_t, (0..10).collect::<Vec<_>>());
+  }
+}
+
+#[test]
+fn test_drop_long_backlog() {
+  let (mut s, r) = new_multi_chan();
+  for i in 0..200_000 {
+    s = s.send(i).unwrap();
+  }
+  drop(r);
+  assert!(s.send(0).is_err());
+}
+
+#[test]
+fn test_drop_oldest_frees_displaced() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  // Counts the messages that are alive.
+  struct Counted(u32, Arc<AtomicUsize>);
+
+  impl Drop for Counted {
+    fn drop(&mut self) {
+      self.1.fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+
+  let live = Arc::new(AtomicUsize::new(0));
+  let (mut s, r) = new_bounded_multi_chan_with_policy(2, OverflowPolicy::DropOldest);
+  for i in 0..100_000 {
+    live.fetch_add(1, Ordering::SeqCst);
+    s = s.send(Counted(i, live.clone())).unwrap();
+    assert!(live.load(Ordering::SeqCst) <= 2);
+  }
+  // Only the two messages the receiver will get are still around, and the
+  // sender only remembers the channels they are in.
+  assert_eq!(s.sent.len(), 2);
+  drop(s);
+  let received: Vec<_> = r.into_iter().map(|c| c.0).collect();
+  assert_eq!(received, vec![99_998, 99_999]);
+  assert_eq!(live.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_reserve() {
+  let (mut s, mut r) = new_bounded_multi_chan_with_policy(2, OverflowPolicy::Fail);
+  s.try_send(1).unwrap();
+  let permit = s.try_reserve().unwrap();
+  permit.send(2).unwrap();
+  assert!(s.try_reserve().is_none());
+  assert_eq!(r.try_recv(), Ok(1));
+
+  // A permit that is dropped unused gives its capacity back.
+  drop(s.reserve());
+  s.try_send(3).unwrap();
+  assert!(s.try_reserve().is_none());
+  assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![2, 3]);
+
+  // Sending with a permit only fails once the receiver is gone.
+  let permit = s.reserve();
+  drop(r);
+  assert_eq!(permit.send(4), Err(SendError(4)));
+
+  let (mut s, mut r) = new_multi_chan();
+  s.reserve().send("unbounded").unwrap();
+  assert_eq!(r.try_recv(), Ok("unbounded"));
+}
+
+#[test]
+fn test_shared_permit_blocks_other_senders() {
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  let (s, r) = new_bounded_multi_chan(1);
+  let s = s.into_shared();
+  let permit = s.reserve();
+  let sent = Arc::new(AtomicBool::new(false));
+  let (other, sent2) = (s.clone(), sent.clone());
+  let h = thread::spawn(move || {
+    other.send("other").unwrap();
+    sent2.store(true, Ordering::SeqCst);
+  });
+  thread::sleep(Duration::from_millis(50));
+  assert!(!sent.load(Ordering::SeqCst));
+
+  // The blocked sender does not keep the permit from being used.
+  permit.send("reserved").unwrap();
+  drop(s);
+  assert_eq!(r.into_iter().collect::<Vec<_>>(), vec!["reserved", "other"]);
+  h.join().unwrap();
+  assert!(sent.load(Ordering::SeqCst));
+}
@@ -0,0 +1,176 @@
+use std::any::Any;
+use std::fmt;
+use std::thread::{self, JoinHandle};
+
+use crate::sync::CancelToken;
+use crate::threads::Lifecycle;
+
+/* Structured concurrency.
+
+A `Nursery<T>` owns the threads spawned through it. Every child returns a `T`,
+and `close()` joins all of them and returns their results in spawn order. A
+child that panics cancels the nursery's `CancelToken`, which every child
+receives, so its siblings can stop early. A nursery that is dropped without
+being closed, e.g. on an error path, cancels the token and then joins its
+children, so no child outlives the nursery that spawned it (unless the nursery
+itself is leaked with `mem::forget`). Children that wait for cancellation are
+woken up by this instead of blocking the drop.
+
+Children are named `nursery-<n>` after their position in spawn order. */
+
+// The payload of a child that panicked.
+
+pub struct Panic(pub Box<dyn Any + Send + 'static>);
+
+impl Panic {
+  // The panic message, if the payload was a string (as with `panic!`).
+
+  pub fn message(&self) -> Option<&str> {
+    if let Some(s) = self.0.downcast_ref::<&'static str>() {
+      Some(s)
+    } else {
+      self.0.downcast_ref::<String>().map(|s| s.as_str())
+    }
+  }
+}
+
+impl fmt::Debug for Panic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.message() {
+      Some(msg) => write!(f, "Panic({:?})", msg),
+      None => f.write_str("Panic(..)"),
+    }
+  }
+}
+
+pub struct Nursery<T> {
+  children: Vec<JoinHandle<T>>,
+  token: CancelToken,
+}
+
+// Cancels the token if the child unwinds before returning.
+
+struct CancelOnPanic(CancelToken);
+
+impl Drop for CancelOnPanic {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.0.cancel();
+    }
+  }
+}
+
+impl<T: Send + 'static> Default for Nursery<T> {
+  fn default() -> Self {
+    Nursery::new()
+  }
+}
+
+impl<T: Send + 'static> Nursery<T> {
+  pub fn new() -> Nursery<T> {
+    Nursery { children: Vec::new(), token: CancelToken::new() }
+  }
+
+  // The token that is cancelled when a child fails, or when `cancel` is called.
+
+  pub fn token(&self) -> &CancelToken {
+    &self.token
+  }
+
+  // Asks all children to stop.
+
+  pub fn cancel(&self) {
+    self.token.cancel();
+  }
+
+  pub fn spawn<F>(&mut self, f: F)
+  where
+    F: FnOnce(CancelToken) -> T + Send + 'static,
+  {
+    let token = self.token.clone();
+    let name = format!("nursery-{}", self.children.len());
+    let child = thread::Builder::new()
+      .name(name.clone())
+      .spawn(move || {
+        let _lifecycle = Lifecycle::start(name);
+        let _guard = CancelOnPanic(token.clone());
+        f(token)
+      })
+      .expect("failed to spawn nursery child");
+    self.children.push(child);
+  }
+
+  // Joins all children and returns their results in the order they were
+  // spawned.
+
+  pub fn close(mut self) -> Vec<Result<T, Panic>> {
+    self.children.drain(..).map(|h| h.join().map_err(Panic)).collect()
+  }
+}
+
+impl<T> Drop for Nursery<T> {
+  fn drop(&mut self) {
+    self.token.cancel();
+    for h in self.children.drain(..) {
+      let _ = h.join();
+    }
+  }
+}
+
+#[test]
+fn test_nursery_results_in_order() {
+  let mut n = Nursery::new();
+  for i in 0..5 {
+    n.spawn(move |_| i * 10);
+  }
+  let results: Vec<_> = n.close().into_iter().map(|r| r.unwrap()).collect();
+  assert_eq!(results, vec![0, 10, 20, 30, 40]);
+}
+
+#[test]
+fn test_nursery_panic_cancels_siblings() {
+  use std::time::Duration;
+
+  let mut n = Nursery::new();
+  n.spawn(|token| {
+    // Without cancellation this would take 10 seconds.
+    token.wait_timeout(Duration::from_secs(10))
+  });
+  n.spawn(|_| -> bool { panic!("child failed") });
+  let results = n.close();
+  assert!(results[0].as_ref().unwrap());
+  assert_eq!(results[1].as_ref().unwrap_err().message(), Some("child failed"));
+}
+
+#[test]
+fn test_nursery_joins_on_drop() {
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  let done = Arc::new(AtomicBool::new(false));
+  {
+    let mut n = Nursery::new();
+    let d = done.clone();
+    n.spawn(move |_| {
+      thread::sleep(Duration::from_millis(20));
+      d.store(true, Ordering::SeqCst);
+    });
+  }
+  assert!(done.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_nursery_drop_cancels() {
+  use std::time::{Duration, Instant};
+
+  let start = Instant::now();
+  {
+    let mut n = Nursery::new();
+    n.spawn(|token| {
+      // Without cancellation this would take 10 seconds.
+      token.wait_timeout(Duration::from_secs(10));
+    });
+  }
+  assert!(start.elapsed() < Duration::from_secs(5));
+}
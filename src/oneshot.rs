@@ -0,0 +1,449 @@
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::errors::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+use crate::select::{Selectable, Signal};
+
+/* One-shot channels.
+
+A one-shot channel is a channel on which you can send one message. The
+representation of the channel is a `Mutex` around an `Option<T>`. The option
+will be `None` if the message has not been sent yet, and `Some(msg)` if the
+message has been sent. Next to the option, the mutex also protects flags that
+record whether the sender and the receiver still exist. This lets the receiver
+tell "not sent yet" apart from "will never be sent", and lets the sender hand
+the message back when nobody is left to receive it.
+
+Both `send` and `recv` take `self`, so the type system guarantees that at most
+one message is sent and received.
+
+The receiver does not spin while waiting: it blocks on a `Condvar` that the
+sender notifies after storing the message. This is also correct under an unfair
+scheduler, because a thread waiting on a condition variable is not runnable and
+the scheduler must run some other thread. */
+
+// Representation of the one-shot channel in memory
+
+struct Repr<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar,
+}
+
+// The state protected by the mutex
+
+struct State<T> {
+  val: Option<T>,
+  // Cleared when the `Send<T>` is dropped, whether or not it sent a message.
+  sender_alive: bool,
+  // Cleared when the `Recv<T>` is dropped.
+  receiver_alive: bool,
+  // Signals of the `Select`s waiting on this channel.
+  watchers: Vec<Arc<Signal>>,
+}
+
+impl<T> State<T> {
+  fn notify_watchers(&self) {
+    for w in &self.watchers {
+      w.notify();
+    }
+  }
+}
+
+// The capability held by the sender
+
+pub struct Send<T> {
+  repr: Arc<Repr<T>>
+}
+
+// The capability held by the receiver
+
+pub struct Recv<T> {
+  repr: Arc<Repr<T>>
+}
+
+// This function creates a new one-shot channel
+
+pub fn new_chan<T>() -> (Send<T>, Recv<T>) {
+  let state = State { val: None, sender_alive: true, receiver_alive: true, watchers: Vec::new() };
+  let repr = Arc::new(Repr { state: Mutex::new(state), cond: Condvar::new() });
+  (Send { repr: repr.clone() }, Recv { repr })
+}
+
+// The receiver will acquire the mutex, and check if the option is `Some(msg)`
+// If it is, we will return the `msg` in the option.
+// If the option is `None` and the sender is gone, no message will arrive.
+// Otherwise we wait on the condition variable, which unlocks the mutex while
+// waiting and locks it again when we are woken up.
+
+impl<T> Recv<T> {
+  pub fn recv(self) -> Result<T, RecvError> {
+    self.recv_in_place()
+  }
+
+  // `recv` for a receiver that is borrowed, as in `MultiRecv`, whose `Drop`
+  // keeps the receiver from being moved out.
+
+  pub(crate) fn recv_in_place(&self) -> Result<T, RecvError> {
+    let mut x = self.repr.state.lock().unwrap();
+    loop {
+      // We take the option out of the mutex and replace the value in the
+      // mutex with `None`. The `option.take()` function does this for us.
+      match x.val.take() {
+        Some(msg) => return Ok(msg),
+        None if !x.sender_alive => return Err(RecvError::Disconnected),
+        // Wakeups can be spurious, so check the option again afterwards.
+        None => x = self.repr.cond.wait(x).unwrap(),
+      }
+    }
+  }
+
+  // Returns the message if it has already been sent, without blocking. This
+  // takes the message out of the channel, so a later `try_recv` or `recv` will
+  // not see it again.
+
+  pub fn try_recv(&self) -> Result<T, TryRecvError> {
+    let mut x = self.repr.state.lock().unwrap();
+    match x.val.take() {
+      Some(msg) => Ok(msg),
+      None if !x.sender_alive => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  // Whether the message has been sent and is waiting to be received.
+
+  pub fn is_ready(&self) -> bool {
+    self.repr.state.lock().unwrap().val.is_some()
+  }
+
+  // Gives access to the message without taking it out of the channel. The
+  // returned guard holds the channel's mutex, so keep it only briefly: the
+  // sender cannot finish dropping while it is held.
+
+  pub fn peek(&self) -> Option<Peek<'_, T>> {
+    let x = self.repr.state.lock().unwrap();
+    if x.val.is_some() {
+      Some(Peek { guard: x })
+    } else {
+      None
+    }
+  }
+
+  // Like `recv`, but gives up after `timeout`. Like `try_recv` it only borrows
+  // the receiver, so the caller can try again after a timeout.
+
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+    self.recv_deadline(Instant::now() + timeout)
+  }
+
+  // Like `recv_timeout`, but gives up at an absolute `deadline`. The remaining
+  // time is recomputed from the deadline after every wakeup, so spurious
+  // wakeups do not extend the wait.
+
+  pub fn recv_deadline(&self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+    let mut x = self.repr.state.lock().unwrap();
+    loop {
+      if let Some(msg) = x.val.take() {
+        return Ok(msg);
+      }
+      if !x.sender_alive {
+        return Err(RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(RecvTimeoutError::Timeout);
+      }
+      x = self.repr.cond.wait_timeout(x, deadline - now).unwrap().0;
+    }
+  }
+}
+
+// A borrowed view of a message that has been sent but not yet received.
+
+pub struct Peek<'a, T> {
+  guard: MutexGuard<'a, State<T>>,
+}
+
+impl<T> Deref for Peek<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // `peek` only creates a `Peek` when the message is present, and nothing can
+    // take it out while the guard is held.
+    self.guard.val.as_ref().unwrap()
+  }
+}
+
+// Dropping the receiver tells the sender that sending is pointless. A message
+// that was already sent but not received is dropped along with it.
+
+impl<T> Drop for Recv<T> {
+  fn drop(&mut self) {
+    let msg = {
+      let mut x = self.repr.state.lock().unwrap_or_else(|e| e.into_inner());
+      x.receiver_alive = false;
+      x.val.take()
+    };
+    // Drop the message outside the lock, its destructor may take a while.
+    drop(msg);
+  }
+}
+
+// The sender acquires the mutex, stores `Some(msg)` in the mutex, and wakes
+// up the receiver if it is waiting. If the receiver is gone, the message is
+// handed back in the error instead.
+
+impl<T> Send<T> {
+  pub fn send(self, msg: T) -> Result<(), SendError<T>> {
+    let mut x = self.repr.state.lock().unwrap();
+    if !x.receiver_alive {
+      return Err(SendError(msg));
+    }
+    x.val = Some(msg);
+    self.repr.cond.notify_one();
+    x.notify_watchers();
+    Ok(())
+  }
+}
+
+impl<T> Send<T> {
+  // Whether the receiver is gone, so that sending would fail.
+
+  pub(crate) fn is_closed(&self) -> bool {
+    !self.repr.state.lock().unwrap().receiver_alive
+  }
+}
+
+// Dropping the sender (also after `send`, which consumes it) tells a waiting
+// receiver that no further message will arrive. The receiver still gets a
+// message that was sent before the drop.
+
+impl<T> Drop for Send<T> {
+  fn drop(&mut self) {
+    // Do not panic in `drop`: the state is consistent even if the mutex is poisoned.
+    let mut x = self.repr.state.lock().unwrap_or_else(|e| e.into_inner());
+    x.sender_alive = false;
+    self.repr.cond.notify_one();
+    x.notify_watchers();
+  }
+}
+
+/* Raw parts.
+
+`into_raw` turns a handle into a plain pointer, e.g. to pass it through an FFI
+boundary or to store it as the `usize` context of an OS callback, and
+`from_raw` turns it back into the handle. The pointer keeps the channel alive:
+it holds the handle's reference to the shared `Repr<T>`, and while it is in
+raw form the other side still sees the handle as alive. Converting the handle
+back is the only way to release it. */
+
+impl<T> Send<T> {
+  pub fn into_raw(self) -> *const () {
+    let this = ManuallyDrop::new(self);
+    // Safety: `this` is never dropped, so the `Arc` is moved out exactly once.
+    Arc::into_raw(unsafe { std::ptr::read(&this.repr) }) as *const ()
+  }
+
+  /// # Safety
+  ///
+  /// `ptr` must have been returned by `Send::<T>::into_raw` for the same `T`,
+  /// and must be turned back into a handle at most once.
+  pub unsafe fn from_raw(ptr: *const ()) -> Send<T> {
+    Send { repr: Arc::from_raw(ptr as *const Repr<T>) }
+  }
+}
+
+impl<T> Recv<T> {
+  pub fn into_raw(self) -> *const () {
+    let this = ManuallyDrop::new(self);
+    // Safety: `this` is never dropped, so the `Arc` is moved out exactly once.
+    Arc::into_raw(unsafe { std::ptr::read(&this.repr) }) as *const ()
+  }
+
+  /// # Safety
+  ///
+  /// `ptr` must have been returned by `Recv::<T>::into_raw` for the same `T`,
+  /// and must be turned back into a handle at most once.
+  pub unsafe fn from_raw(ptr: *const ()) -> Recv<T> {
+    Recv { repr: Arc::from_raw(ptr as *const Repr<T>) }
+  }
+}
+
+/* Slots.
+
+Under `OverflowPolicy::DropOldest`, a multi-shot sender has to discard the
+oldest message that has been sent but not received. That message sits in a
+one-shot channel that only the receiver can reach, so the sender keeps a
+`Slot` of the channel: a second handle on the message that does not count as a
+receiver. */
+
+pub(crate) struct Slot<T> {
+  repr: Arc<Repr<T>>,
+}
+
+impl<T> Send<T> {
+  pub(crate) fn slot(&self) -> Slot<T> {
+    Slot { repr: self.repr.clone() }
+  }
+}
+
+impl<T> Slot<T> {
+  // Whether there is no message in the channel, e.g. because it was received.
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.repr.state.lock().unwrap().val.is_none()
+  }
+
+  // Moves the message of `next` into this channel and returns the message it
+  // replaces. Both channels stay locked for the whole move, this one first, so
+  // a receiver never finds this channel empty in between. Returns `None`, and
+  // changes nothing, if either channel is empty.
+
+  pub(crate) fn replace_from(&self, next: &Slot<T>) -> Option<T> {
+    let mut x = self.repr.state.lock().unwrap();
+    x.val.as_ref()?;
+    let moved = next.repr.state.lock().unwrap().val.take()?;
+    x.val.replace(moved)
+  }
+}
+
+// A receiver can be selected on. Unlike `is_ready`, it counts as ready for
+// `Select` also when the sender is gone, as `try_recv` then returns right away.
+
+impl<T> Selectable for Recv<T> {
+  fn select_ready(&self) -> bool {
+    let x = self.repr.state.lock().unwrap();
+    x.val.is_some() || !x.sender_alive
+  }
+
+  fn watch(&self, signal: &Arc<Signal>) {
+    self.repr.state.lock().unwrap().watchers.push(signal.clone());
+  }
+
+  fn unwatch(&self, signal: &Arc<Signal>) {
+    self.repr.state.lock().unwrap().watchers.retain(|w| !Arc::ptr_eq(w, signal));
+  }
+}
+
+#[test]
+fn test_recv_blocks_until_send() {
+  use std::thread;
+  use std::time::Duration;
+
+  let (s, r) = new_chan();
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(50));
+    s.send(42).unwrap();
+  });
+  assert_eq!(r.recv(), Ok(42));
+  h.join().unwrap();
+}
+
+#[test]
+fn test_try_recv() {
+  let (s, r) = new_chan();
+  assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+  s.send("hello").unwrap();
+  assert_eq!(r.try_recv(), Ok("hello"));
+  assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_recv_timeout() {
+  use std::thread;
+
+  let (s, r) = new_chan();
+  assert_eq!(r.recv_timeout(Duration::from_millis(20)), Err(RecvTimeoutError::Timeout));
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(20));
+    s.send(7).unwrap();
+  });
+  assert_eq!(r.recv_timeout(Duration::from_secs(5)), Ok(7));
+  h.join().unwrap();
+}
+
+#[test]
+fn test_recv_deadline_shared() {
+  let (_s1, r1) = new_chan::<i32>();
+  let (_s2, r2) = new_chan::<i32>();
+  let start = Instant::now();
+  let deadline = start + Duration::from_millis(200);
+  assert_eq!(r1.recv_deadline(deadline), Err(RecvTimeoutError::Timeout));
+  assert_eq!(r2.recv_deadline(deadline), Err(RecvTimeoutError::Timeout));
+  let elapsed = start.elapsed();
+  // The second receiver must not wait another 200ms.
+  assert!(elapsed >= Duration::from_millis(200));
+  assert!(elapsed < Duration::from_millis(400));
+}
+
+#[test]
+fn test_sender_dropped() {
+  use std::thread;
+
+  let (s, r) = new_chan::<i32>();
+  drop(s);
+  assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+  assert_eq!(r.recv_timeout(Duration::from_secs(5)), Err(RecvTimeoutError::Disconnected));
+  assert_eq!(r.recv(), Err(RecvError::Disconnected));
+
+  // A blocked receiver is woken up by the drop.
+  let (s, r) = new_chan::<i32>();
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(20));
+    drop(s);
+  });
+  assert_eq!(r.recv(), Err(RecvError::Disconnected));
+  h.join().unwrap();
+
+  // A message sent before the drop is still delivered.
+  let (s, r) = new_chan();
+  s.send(1).unwrap();
+  assert_eq!(r.recv(), Ok(1));
+}
+
+#[test]
+fn test_receiver_dropped() {
+  let (s, r) = new_chan();
+  drop(r);
+  assert_eq!(s.send(String::from("lost")), Err(SendError(String::from("lost"))));
+}
+
+#[test]
+fn test_is_ready_and_peek() {
+  let (s, r) = new_chan();
+  assert!(!r.is_ready());
+  assert!(r.peek().is_none());
+  s.send(vec![1, 2, 3]).unwrap();
+  assert!(r.is_ready());
+  assert_eq!(r.peek().unwrap().len(), 3);
+  // Peeking does not consume the message.
+  assert!(r.is_ready());
+  assert_eq!(r.recv(), Ok(vec![1, 2, 3]));
+}
+
+#[test]
+fn test_raw_round_trip() {
+  use std::thread;
+
+  let (s, r) = new_chan::<String>();
+  // Pass both handles around as plain integers, as an OS callback would.
+  let s = s.into_raw() as usize;
+  let r = r.into_raw() as usize;
+  let h = thread::spawn(move || {
+    let s = unsafe { Send::<String>::from_raw(s as *const ()) };
+    s.send("raw".to_string()).unwrap();
+  });
+  let r = unsafe { Recv::<String>::from_raw(r as *const ()) };
+  assert_eq!(r.recv(), Ok("raw".to_string()));
+  h.join().unwrap();
+
+  // A handle in raw form still counts as alive, and dropping it after the
+  // round trip disconnects the channel as usual.
+  let (s, r) = new_chan::<u8>();
+  let raw = s.into_raw();
+  assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+  drop(unsafe { Send::<u8>::from_raw(raw) });
+  assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+}
@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::errors::{AggregateError, Collector};
+use crate::multishot::{new_bounded_multi_chan, new_multi_chan, MultiRecv, MultiSend};
+use crate::threads;
+
+/* Bounded parallelism helpers. */
+
+// Calls `f` on every item of `items`, with at most `limit` calls running at the
+// same time. Each of the `limit` worker threads pulls the next item as soon as
+// it is done with the previous one. Failed items do not stop the others; all
+// errors are returned together, attributed to the index of the item that
+// produced them. A panic in `f` is propagated to the caller once all workers
+// have stopped.
+
+pub fn for_each_concurrent<I, F, E>(items: I, limit: usize, f: F) -> Result<(), AggregateError<E>>
+where
+  I: IntoIterator,
+  I::IntoIter: Send,
+  I::Item: Send,
+  F: Fn(I::Item) -> Result<(), E> + Sync,
+  E: Send,
+{
+  assert!(limit > 0, "for_each_concurrent: limit must be positive");
+  let items = Mutex::new(items.into_iter().enumerate());
+  let errors = Collector::new();
+
+  thread::scope(|s| {
+    for w in 0..limit {
+      let (items, errors, f) = (&items, errors.clone(), &f);
+      threads::spawn_scoped(s, format!("for_each_concurrent-{}", w), move || loop {
+          // Only hold the lock while taking the next item, not while running `f`.
+          let next = items.lock().unwrap().next();
+          match next {
+            Some((i, item)) => {
+              if let Err(e) = f(item) {
+                errors.push_task(format!("item {}", i), e);
+              }
+            }
+            None => break,
+          }
+      });
+    }
+  });
+
+  errors.finish()
+}
+
+// Calls `f` on every item of `items` on `limit` worker threads, like
+// `for_each_concurrent`, and returns the results in the order of the input.
+// Each worker keeps its results tagged with the index of the item; they are
+// put back in place once all workers are done. A panic in `f` is propagated
+// to the caller once all workers have stopped.
+
+pub fn map_ordered<I, F, U>(items: I, limit: usize, f: F) -> Vec<U>
+where
+  I: IntoIterator,
+  I::IntoIter: Send,
+  I::Item: Send,
+  F: Fn(I::Item) -> U + Sync,
+  U: Send,
+{
+  assert!(limit > 0, "map_ordered: limit must be positive");
+  let items = Mutex::new(items.into_iter().enumerate());
+
+  let tagged: Vec<(usize, U)> = thread::scope(|s| {
+    let workers: Vec<_> = (0..limit).map(|w| {
+      let (items, f) = (&items, &f);
+      threads::spawn_scoped(s, format!("map_ordered-{}", w), move || {
+          let mut done = Vec::new();
+          loop {
+            let next = items.lock().unwrap().next();
+            match next {
+              Some((i, item)) => done.push((i, f(item))),
+              None => break done,
+            }
+          }
+      })
+    }).collect();
+    workers.into_iter().flat_map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+  });
+
+  let mut slots: Vec<Option<U>> = (0..tagged.len()).map(|_| None).collect();
+  for (i, u) in tagged {
+    slots[i] = Some(u);
+  }
+  slots.into_iter().map(|u| u.unwrap()).collect()
+}
+
+/* `par_map` parallelizes one stage of a stream. It consumes a multi-shot
+receiver, runs `f` on `n` worker threads, and returns a receiver for the
+results. With `ordered` set, the results come out in the order of the input;
+otherwise in the order they are done.
+
+At most `n` messages are in flight between the input and the output: a worker
+takes a slot before it takes a message, and the slot is only given back once
+the result has been sent to the output. A result that is done early waits for
+the earlier ones while holding its slot, so the reorder buffer cannot grow
+beyond `n` either. The output is bounded to `n` messages as well, so a slow
+consumer holds up the workers.
+
+If `f` panics, the output is closed after the results before the failed one:
+with `ordered` set, the results of all earlier messages, including ones that
+are still being worked on at the time; otherwise, the results that were done
+first. The panic ends the worker thread as usual. */
+
+struct Slots {
+  free: Mutex<usize>,
+  cond: Condvar,
+}
+
+// One message's claim on the in-flight limit of `par_map`. Given back when
+// dropped.
+
+struct Slot(Arc<Slots>);
+
+impl Slot {
+  fn acquire(slots: &Arc<Slots>) -> Slot {
+    let free = slots.free.lock().unwrap();
+    let mut free = slots.cond.wait_while(free, |free| *free == 0).unwrap();
+    *free -= 1;
+    Slot(slots.clone())
+  }
+}
+
+impl Drop for Slot {
+  fn drop(&mut self) {
+    *self.0.free.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+    self.0.cond.notify_one();
+  }
+}
+
+impl<T: Send + 'static> MultiRecv<T> {
+  pub fn par_map<U, F>(self, n: usize, ordered: bool, f: F) -> MultiRecv<U>
+  where
+    U: Send + 'static,
+    F: Fn(T) -> U + Send + Sync + 'static,
+  {
+    assert!(n > 0, "par_map: n must be positive");
+    let input = Arc::new(Mutex::new(self.into_iter().enumerate()));
+    let slots = Arc::new(Slots { free: Mutex::new(n), cond: Condvar::new() });
+    let f = Arc::new(f);
+    let (done, results) = new_multi_chan();
+    let done = done.into_shared();
+
+    for w in 0..n {
+      let (input, slots, f, done) = (input.clone(), slots.clone(), f.clone(), done.clone());
+      threads::spawn(format!("par_map-{}", w), move || loop {
+          let slot = Slot::acquire(&slots);
+          let next = input.lock().unwrap().next();
+          let Some((i, msg)) = next else { break };
+          match panic::catch_unwind(AssertUnwindSafe(|| f(msg))) {
+            Ok(u) => {
+              if done.send((i, Some(u), slot)).is_err() {
+                break;
+              }
+            }
+            Err(e) => {
+              let _ = done.send((i, None, slot));
+              panic::resume_unwind(e);
+            }
+          }
+      });
+    }
+
+    let (output, mapped) = new_bounded_multi_chan(n);
+    threads::spawn("par_map-collect".to_string(), move || forward_mapped(results, output, ordered));
+    mapped
+  }
+}
+
+// Forwards the results of the `par_map` workers, restoring the input order if
+// `ordered` is set. `None` marks a message on which `f` panicked.
+
+fn forward_mapped<U>(results: MultiRecv<(usize, Option<U>, Slot)>, mut output: MultiSend<U>, ordered: bool) {
+  let mut pending = BTreeMap::new();
+  let mut next = 0;
+  // The first message on which `f` panicked. Later results are dropped, and the
+  // output is closed once all earlier ones have been sent.
+  let mut failed = usize::MAX;
+  for (i, u, slot) in results {
+    match u {
+      Some(u) if i < failed => {
+        pending.insert(i, (u, slot));
+      }
+      Some(_) => {}
+      None if !ordered => return,
+      None => {
+        failed = failed.min(i);
+        pending.split_off(&failed);
+      }
+    }
+    while let Some(&first) = pending.keys().next() {
+      if ordered && first != next {
+        break;
+      }
+      // The slot is given back once the result has been sent.
+      let (u, _slot) = pending.remove(&first).unwrap();
+      next += 1;
+      output = match output.send(u) {
+        Ok(output) => output,
+        Err(_) => return,
+      };
+    }
+    if next >= failed {
+      return;
+    }
+  }
+}
+
+#[test]
+fn test_for_each_concurrent_limit() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::time::Duration;
+
+  let running = AtomicUsize::new(0);
+  let max_running = AtomicUsize::new(0);
+  let done = AtomicUsize::new(0);
+  let r: Result<(), AggregateError<()>> = for_each_concurrent(0..32, 4, |_| {
+    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+    max_running.fetch_max(now, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(5));
+    running.fetch_sub(1, Ordering::SeqCst);
+    done.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+  });
+  assert!(r.is_ok());
+  assert_eq!(done.load(Ordering::SeqCst), 32);
+  assert!(max_running.load(Ordering::SeqCst) <= 4);
+}
+
+#[test]
+fn test_for_each_concurrent_errors() {
+  let r = for_each_concurrent(0..10, 3, |i| {
+    if i % 3 == 0 { Err(i) } else { Ok(()) }
+  });
+  let mut failed: Vec<_> = r.unwrap_err().errors.into_iter().map(|e| e.error).collect();
+  failed.sort();
+  assert_eq!(failed, vec![0, 3, 6, 9]);
+}
+
+#[test]
+fn test_map_ordered() {
+  use std::time::Duration;
+
+  // Earlier items take longer, so they finish last but must still come first.
+  let out = map_ordered(0..20u64, 4, |i| {
+    thread::sleep(Duration::from_millis(20 - i));
+    i * 10
+  });
+  assert_eq!(out, (0..20).map(|i| i * 10).collect::<Vec<_>>());
+  assert!(map_ordered(Vec::<u8>::new(), 2, |i| i).is_empty());
+}
+
+#[test]
+fn test_par_map() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::time::Duration;
+
+  let source = |n: u64| {
+    let (mut s, r) = new_multi_chan();
+    for i in 0..n {
+      s = s.send(i).unwrap();
+    }
+    r
+  };
+
+  let running = Arc::new(AtomicUsize::new(0));
+  let max_running = Arc::new(AtomicUsize::new(0));
+  let (run, max) = (running.clone(), max_running.clone());
+  let out: Vec<_> = source(20).par_map(4, true, move |i| {
+    let now = run.fetch_add(1, Ordering::SeqCst) + 1;
+    max.fetch_max(now, Ordering::SeqCst);
+    // Earlier messages take longer, so they are done last.
+    thread::sleep(Duration::from_millis(20 - i));
+    run.fetch_sub(1, Ordering::SeqCst);
+    i * 10
+  }).into_iter().collect();
+  assert_eq!(out, (0..20).map(|i| i * 10).collect::<Vec<_>>());
+  assert!(max_running.load(Ordering::SeqCst) <= 4);
+
+  let mut out: Vec<_> = source(20).par_map(3, false, |i| i + 1).into_iter().collect();
+  out.sort();
+  assert_eq!(out, (1..21).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_par_map_panic_closes_output() {
+  use std::time::Duration;
+
+  let source = |n: u64| {
+    let (mut s, r) = new_multi_chan();
+    for i in 0..n {
+      s = s.send(i).unwrap();
+    }
+    r
+  };
+
+  let out: Vec<_> = source(10).par_map(2, true, |i| {
+    assert_ne!(i, 5, "par_map test panic");
+    i
+  }).into_iter().collect();
+  assert_eq!(out, vec![0, 1, 2, 3, 4]);
+
+  // The failure is reported while an earlier message is still being worked on,
+  // whose result must still come out.
+  let out: Vec<_> = source(6).par_map(4, true, |i| {
+    if i == 0 {
+      thread::sleep(Duration::from_millis(100));
+    }
+    assert_ne!(i, 1, "par_map test panic");
+    i
+  }).into_iter().collect();
+  assert_eq!(out, vec![0]);
+}
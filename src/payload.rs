@@ -0,0 +1,168 @@
+use std::fmt;
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::Arc;
+
+/* Cheaply cloneable, immutable byte buffers for message payloads.
+
+A `Payload` is a view into a shared `Arc<[u8]>`. Cloning it or taking a
+sub-slice only bumps the reference count, so byte messages can be routed,
+duplicated and split into parts without copying the underlying buffer. */
+
+#[derive(Clone)]
+pub struct Payload {
+  data: Arc<[u8]>,
+  start: usize,
+  end: usize,
+}
+
+impl Payload {
+  pub fn new() -> Payload {
+    Payload::from(Vec::new())
+  }
+
+  // Copies `bytes` into a new buffer.
+
+  pub fn copy_from_slice(bytes: &[u8]) -> Payload {
+    Payload::from(Arc::<[u8]>::from(bytes))
+  }
+
+  pub fn len(&self) -> usize {
+    self.end - self.start
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.start == self.end
+  }
+
+  // A payload sharing the bytes in `range` (relative to this payload) with
+  // this one. Panics if the range is out of bounds.
+
+  pub fn slice(&self, range: impl RangeBounds<usize>) -> Payload {
+    let start = match range.start_bound() {
+      Bound::Included(&n) => n,
+      Bound::Excluded(&n) => n + 1,
+      Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+      Bound::Included(&n) => n + 1,
+      Bound::Excluded(&n) => n,
+      Bound::Unbounded => self.len(),
+    };
+    assert!(start <= end, "slice start {} is past its end {}", start, end);
+    assert!(end <= self.len(), "slice end {} is out of bounds for length {}", end, self.len());
+    Payload { data: self.data.clone(), start: self.start + start, end: self.start + end }
+  }
+
+  // Splits off and returns the first `at` bytes; `self` keeps the rest.
+
+  pub fn split_to(&mut self, at: usize) -> Payload {
+    let head = self.slice(..at);
+    self.start += at;
+    head
+  }
+
+  // Whether both payloads are views into the same buffer.
+
+  pub fn shares_buffer(&self, other: &Payload) -> bool {
+    Arc::ptr_eq(&self.data, &other.data)
+  }
+}
+
+impl Default for Payload {
+  fn default() -> Self {
+    Payload::new()
+  }
+}
+
+impl From<Arc<[u8]>> for Payload {
+  fn from(data: Arc<[u8]>) -> Payload {
+    let end = data.len();
+    Payload { data, start: 0, end }
+  }
+}
+
+impl From<Vec<u8>> for Payload {
+  fn from(v: Vec<u8>) -> Payload {
+    Payload::from(Arc::<[u8]>::from(v))
+  }
+}
+
+impl From<String> for Payload {
+  fn from(s: String) -> Payload {
+    Payload::from(s.into_bytes())
+  }
+}
+
+impl From<&[u8]> for Payload {
+  fn from(bytes: &[u8]) -> Payload {
+    Payload::copy_from_slice(bytes)
+  }
+}
+
+impl From<&str> for Payload {
+  fn from(s: &str) -> Payload {
+    Payload::copy_from_slice(s.as_bytes())
+  }
+}
+
+impl Deref for Payload {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.data[self.start..self.end]
+  }
+}
+
+impl AsRef<[u8]> for Payload {
+  fn as_ref(&self) -> &[u8] {
+    self
+  }
+}
+
+impl PartialEq for Payload {
+  fn eq(&self, other: &Payload) -> bool {
+    **self == **other
+  }
+}
+
+impl Eq for Payload {}
+
+impl PartialEq<[u8]> for Payload {
+  fn eq(&self, other: &[u8]) -> bool {
+    **self == *other
+  }
+}
+
+impl fmt::Debug for Payload {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Payload({:?})", &**self)
+  }
+}
+
+#[test]
+fn test_payload_slicing_shares_buffer() {
+  let p = Payload::from("hello world");
+  let hello = p.slice(..5);
+  let world = p.slice(6..);
+  assert_eq!(&*hello, b"hello");
+  assert_eq!(&*world, b"world");
+  assert_eq!(world.slice(1..=2), Payload::from("or"));
+  assert!(hello.shares_buffer(&world));
+  assert!(p.clone().shares_buffer(&p));
+}
+
+#[test]
+fn test_payload_split_to() {
+  let mut p = Payload::from(vec![1, 2, 3, 4, 5]);
+  let head = p.split_to(2);
+  assert_eq!(head, Payload::from(vec![1, 2]));
+  assert_eq!(p, Payload::from(vec![3, 4, 5]));
+  assert!(head.shares_buffer(&p));
+  assert!(p.split_to(3).len() == 3 && p.is_empty());
+}
+
+#[test]
+#[should_panic]
+fn test_payload_slice_out_of_bounds() {
+  Payload::from("abc").slice(1..4);
+}
@@ -0,0 +1,378 @@
+use std::sync::{Arc, Mutex};
+
+use crate::multishot::{new_bounded_multi_chan, new_multi_chan, MultiRecv, MultiSend};
+use crate::nursery::{Nursery, Panic};
+use crate::threads;
+
+/* Pipelines of worker stages.
+
+A pipeline is a chain of stages, each running on its own thread and connected
+to the next one by a bounded multi-shot channel, so a slow stage makes the
+stages before it wait instead of letting messages pile up:
+
+    Pipeline::source(16, 0..100).map(|n| n * n).filter(|n| n % 2 == 0).sink(f)
+
+The stage threads belong to a `Nursery`, and the `PipelineHandle` returned by
+`sink` joins them all. When the source runs out, it closes its output, and
+each stage closes its own output once it has passed on everything, so the end
+of the stream travels down the pipeline. If a stage stops early, because it
+panicked, the stage before it fails to send and stops as well.
+
+With `into_recv`, the last stage may be waiting for room in the channel to the
+caller's receiver. Joining the handle before that receiver has been read to
+the end, or dropped, therefore deadlocks. Dropping the handle is safe: it does
+not wait for the stages, but leaves them to run to the end of the stream and
+joins them on a thread of its own.
+
+A pipeline started with `stepped` runs all stages on the caller's thread
+instead, for tests that need the same execution on every run. Each message
+goes through every stage before the next one is taken from the source, so
+nothing depends on how threads are scheduled. The stages only run once the
+pipeline is ended, by `sink` or `into_recv`, and a stage that panics panics
+the caller.
+
+`PipelineHandle::snapshot` reports how many messages each stage has received
+and sent so far, e.g. for metrics of a running pipeline. All stages count into
+one table behind a mutex, so a snapshot is a consistent cut without having to
+pass markers down the channels: a stage counts a message as sent before it
+sends it, and the next stage counts it as received after it received it, so
+the difference between the two is what is in the channel between them, and
+never negative. */
+
+pub struct Pipeline<T> {
+  run: Run<T>,
+  capacity: usize,
+  counts: Counts,
+}
+
+enum Run<T> {
+  // Every stage on a thread of its own.
+  Threads {
+    // Declared before `stages`, so that dropping an unfinished pipeline drops
+    // the receiver first, which stops the stages before the nursery joins
+    // them.
+    output: MultiRecv<T>,
+    stages: Nursery<()>,
+  },
+  // All stages on the caller's thread, one message at a time.
+  Stepped(Box<dyn Iterator<Item = T> + Send>),
+}
+
+pub struct PipelineHandle {
+  // `None` for a stepped pipeline, whose stages have already run, and once
+  // `join` has taken it.
+  stages: Option<Nursery<()>>,
+  counts: Counts,
+}
+
+// The messages one stage has received and sent. The source only sends, and a
+// sink only receives.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StageCounts {
+  pub received: u64,
+  pub sent: u64,
+}
+
+// The counts of all stages of a pipeline, in order.
+
+#[derive(Clone, Default)]
+struct Counts(Arc<Mutex<Vec<StageCounts>>>);
+
+impl Counts {
+  // Adds a stage and returns its index.
+
+  fn add(&self) -> usize {
+    let mut counts = self.0.lock().unwrap();
+    counts.push(StageCounts::default());
+    counts.len() - 1
+  }
+
+  fn update(&self, stage: usize, f: impl FnOnce(&mut StageCounts)) {
+    f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner())[stage]);
+  }
+}
+
+// Forwards the results of `f` for each input message until the input is closed
+// or the next stage is gone.
+
+fn forward<T, U>(input: MultiRecv<T>, mut output: MultiSend<U>, mut f: impl FnMut(T) -> Option<U>) {
+  for msg in input {
+    if let Some(out) = f(msg) {
+      output = match output.send(out) {
+        Ok(output) => output,
+        Err(_) => return,
+      };
+    }
+  }
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+  // A pipeline whose first stage sends the items of `items`, with channels of
+  // `capacity` messages between the stages.
+
+  pub fn source<I>(capacity: usize, items: I) -> Pipeline<T>
+  where
+    I: IntoIterator<Item = T> + Send + 'static,
+  {
+    let (mut s, r) = new_bounded_multi_chan(capacity);
+    let counts = Counts::default();
+    let (c, stage) = (counts.clone(), counts.add());
+    let mut stages = Nursery::new();
+    stages.spawn(move |_| {
+      for item in items {
+        c.update(stage, |n| n.sent += 1);
+        s = match s.send(item) {
+          Ok(s) => s,
+          Err(_) => return,
+        };
+      }
+    });
+    Pipeline { run: Run::Threads { output: r, stages }, capacity, counts }
+  }
+
+  // A pipeline that starts from messages produced elsewhere. It has no source
+  // stage to count.
+
+  pub fn from_recv(capacity: usize, input: MultiRecv<T>) -> Pipeline<T> {
+    Pipeline { run: Run::Threads { output: input, stages: Nursery::new() }, capacity, counts: Counts::default() }
+  }
+
+  // A pipeline whose stages all run on the caller's thread, starting from the
+  // items of `items`.
+
+  pub fn stepped<I>(items: I) -> Pipeline<T>
+  where
+    I: IntoIterator<Item = T>,
+    I::IntoIter: Send + 'static,
+  {
+    let counts = Counts::default();
+    let (c, stage) = (counts.clone(), counts.add());
+    let items = items.into_iter().inspect(move |_| c.update(stage, |n| n.sent += 1));
+    Pipeline { run: Run::Stepped(Box::new(items)), capacity: 1, counts }
+  }
+
+  // Adds a stage that runs `f` on every message.
+
+  pub fn map<U: Send + 'static>(self, mut f: impl FnMut(T) -> U + Send + 'static) -> Pipeline<U> {
+    self.stage(move |msg| Some(f(msg)))
+  }
+
+  // Adds a stage that passes on only the messages for which `f` holds.
+
+  pub fn filter(self, mut f: impl FnMut(&T) -> bool + Send + 'static) -> Pipeline<T> {
+    self.stage(move |msg| if f(&msg) { Some(msg) } else { None })
+  }
+
+  fn stage<U: Send + 'static>(self, mut f: impl FnMut(T) -> Option<U> + Send + 'static) -> Pipeline<U> {
+    let Pipeline { run, capacity, counts } = self;
+    let (c, stage) = (counts.clone(), counts.add());
+    let f = move |msg| {
+      c.update(stage, |n| n.received += 1);
+      let out = f(msg);
+      if out.is_some() {
+        c.update(stage, |n| n.sent += 1);
+      }
+      out
+    };
+    let run = match run {
+      Run::Threads { output: input, mut stages } => {
+        let (s, r) = new_bounded_multi_chan(capacity);
+        stages.spawn(move |_| forward(input, s, f));
+        Run::Threads { output: r, stages }
+      }
+      Run::Stepped(input) => Run::Stepped(Box::new(input.filter_map(f))),
+    };
+    Pipeline { run, capacity, counts }
+  }
+
+  // Adds a last stage that consumes every message with `f`. A stepped pipeline
+  // runs to the end before this returns.
+
+  pub fn sink(self, mut f: impl FnMut(T) + Send + 'static) -> PipelineHandle {
+    let Pipeline { run, counts, .. } = self;
+    let (c, stage) = (counts.clone(), counts.add());
+    let f = move |msg| {
+      c.update(stage, |n| n.received += 1);
+      f(msg)
+    };
+    match run {
+      Run::Threads { output: input, mut stages } => {
+        stages.spawn(move |_| input.into_iter().for_each(f));
+        PipelineHandle { stages: Some(stages), counts }
+      }
+      Run::Stepped(input) => {
+        input.for_each(f);
+        PipelineHandle { stages: None, counts }
+      }
+    }
+  }
+
+  // Ends the pipeline in a receiver, for a consumer that is not a stage of
+  // its own. Only join the handle once the receiver is done with. A stepped
+  // pipeline runs to the end before this returns, so its source must be
+  // finite.
+
+  pub fn into_recv(self) -> (MultiRecv<T>, PipelineHandle) {
+    let Pipeline { run, counts, .. } = self;
+    match run {
+      Run::Threads { output, stages } => (output, PipelineHandle { stages: Some(stages), counts }),
+      Run::Stepped(input) => {
+        let (mut s, r) = new_multi_chan();
+        for msg in input {
+          s = s.send(msg).unwrap_or_else(|_| unreachable!("the receiver is still here"));
+        }
+        (r, PipelineHandle { stages: None, counts })
+      }
+    }
+  }
+}
+
+impl PipelineHandle {
+  // Waits for all stages to finish. Returns the panic of the first stage that
+  // panicked, if any.
+
+  pub fn join(mut self) -> Result<(), Panic> {
+    match self.stages.take() {
+      Some(stages) => stages.close().into_iter().collect(),
+      None => Ok(()),
+    }
+  }
+
+  // The counts of every stage so far, in the order the stages were added. The
+  // receiver of `into_recv` is not a stage, so it is not counted.
+
+  pub fn snapshot(&self) -> Vec<StageCounts> {
+    self.counts.0.lock().unwrap().clone()
+  }
+}
+
+// Dropping the nursery would join the stages right here, so move it to a
+// thread that waits for them instead.
+
+impl Drop for PipelineHandle {
+  fn drop(&mut self) {
+    if let Some(stages) = self.stages.take() {
+      threads::spawn("pipeline-join".to_string(), move || stages.close());
+    }
+  }
+}
+
+#[test]
+fn test_pipeline_stages() {
+  let collected = Arc::new(Mutex::new(Vec::new()));
+  let c = collected.clone();
+  Pipeline::source(2, 0..10)
+    .map(|n| n * n)
+    .filter(|n| n % 2 == 0)
+    .sink(move |n| c.lock().unwrap().push(n))
+    .join()
+    .unwrap();
+  assert_eq!(*collected.lock().unwrap(), vec![0, 4, 16, 36, 64]);
+}
+
+#[test]
+fn test_pipeline_stage_panics() {
+  // The panicking stage stops the unbounded source upstream.
+  let (r, handle) = Pipeline::source(1, 0..)
+    .map(|n: u64| if n == 3 { panic!("bad item") } else { n })
+    .into_recv();
+  assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+  assert_eq!(handle.join().unwrap_err().message(), Some("bad item"));
+}
+
+#[test]
+fn test_pipeline_from_recv() {
+  let (s, r) = new_multi_chan();
+  s.send("a").unwrap().send("bb").unwrap().close();
+  let (out, handle) = Pipeline::from_recv(4, r).map(str::len).into_recv();
+  assert_eq!(out.into_iter().collect::<Vec<_>>(), vec![1, 2]);
+  handle.join().unwrap();
+}
+
+#[test]
+fn test_pipeline_handle_dropped_early() {
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::oneshot::new_chan;
+  use crate::testing::expect_flow;
+
+  // The handle is dropped before the output is read, while the stage waits for
+  // room in the channel. Run it on a thread so that a hang fails the test.
+  let (s, done) = new_chan();
+  thread::spawn(move || {
+    expect_flow(|r| Pipeline::from_recv(2, r).map(|n| n + 1).into_recv().0, 0..10, 1..11);
+    let _ = s.send(());
+  });
+  assert_eq!(done.recv_timeout(Duration::from_secs(5)), Ok(()));
+}
+
+#[test]
+fn test_pipeline_stepped() {
+  // Every message goes through all stages before the next one is taken.
+  let trace = Arc::new(Mutex::new(Vec::new()));
+  let (t1, t2, t3) = (trace.clone(), trace.clone(), trace.clone());
+  Pipeline::stepped(0..3)
+    .map(move |n: u32| {
+      t1.lock().unwrap().push(format!("map {}", n));
+      n * 2
+    })
+    .filter(move |n| {
+      t2.lock().unwrap().push(format!("filter {}", n));
+      *n != 2
+    })
+    .sink(move |n| t3.lock().unwrap().push(format!("sink {}", n)))
+    .join()
+    .unwrap();
+  assert_eq!(
+    *trace.lock().unwrap(),
+    ["map 0", "filter 0", "sink 0", "map 1", "filter 2", "map 2", "filter 4", "sink 4"],
+  );
+
+  let (r, handle) = Pipeline::stepped(vec!["a", "bb"]).map(str::len).into_recv();
+  assert_eq!(r.into_iter().collect::<Vec<_>>(), vec![1, 2]);
+  handle.join().unwrap();
+}
+
+#[test]
+fn test_pipeline_snapshot() {
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::oneshot::new_chan;
+
+  // The sink holds on to its first message until released, so the pipeline
+  // fills up behind it.
+  let (release, wait) = new_chan::<()>();
+  let handle = Pipeline::source(2, 0..100u32)
+    .filter(|n| n.is_multiple_of(2))
+    .sink(move |_| {
+      let _ = wait.recv_timeout(Duration::from_secs(10));
+    });
+  thread::sleep(Duration::from_millis(50));
+  let counts = handle.snapshot();
+  assert_eq!(counts.len(), 3);
+  assert_eq!(counts[2].received, 1);
+  // Between two stages are at most the messages in the channel, and the one
+  // the stage before is waiting to send.
+  for pair in counts.windows(2) {
+    assert!(pair[0].sent >= pair[1].received);
+    assert!(pair[0].sent - pair[1].received <= 2 + 1);
+  }
+
+  release.send(()).unwrap();
+  handle.join().unwrap();
+
+  // Once the output has been read to the end, every stage is done counting.
+  let (r, handle) = Pipeline::source(2, 0..100u32).filter(|n| n.is_multiple_of(2)).into_recv();
+  assert_eq!(r.into_iter().count(), 50);
+  assert_eq!(handle.snapshot(), [StageCounts { received: 0, sent: 100 }, StageCounts { received: 100, sent: 50 }]);
+
+  let handle = Pipeline::stepped(0..10u32).map(|n| n + 1).sink(drop);
+  assert_eq!(handle.snapshot(), [
+    StageCounts { received: 0, sent: 10 },
+    StageCounts { received: 10, sent: 10 },
+    StageCounts { received: 10, sent: 0 },
+  ]);
+}
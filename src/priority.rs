@@ -0,0 +1,214 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+
+/* Priority channels.
+
+A priority channel delivers the greatest pending message first, according to
+its `Ord` implementation, instead of the oldest one. Messages that compare
+equal are delivered in the order they were sent. To send jobs with a separate
+priority, send `(priority, job)` pairs, or wrap the job in a type that orders by
+its priority.
+
+Apart from the order, it works like `mpmc::channel`: both ends can be cloned,
+and each message is delivered to exactly one receiver. The pending messages
+are kept in a `BinaryHeap` behind a mutex. */
+
+struct Repr<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar,
+}
+
+struct State<T> {
+  heap: BinaryHeap<Entry<T>>,
+  // Numbers the messages, so equal ones come out in the order they were sent.
+  sent: u64,
+  senders: usize,
+  receivers: usize,
+}
+
+struct Entry<T> {
+  msg: T,
+  seq: Reverse<u64>,
+}
+
+impl<T: Ord> Ord for Entry<T> {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.msg.cmp(&other.msg).then(self.seq.cmp(&other.seq))
+  }
+}
+
+impl<T: Ord> PartialOrd for Entry<T> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl<T: Ord> PartialEq for Entry<T> {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl<T: Ord> Eq for Entry<T> {}
+
+pub struct Sender<T> {
+  repr: Arc<Repr<T>>,
+}
+
+pub struct Receiver<T> {
+  repr: Arc<Repr<T>>,
+}
+
+pub fn channel<T: Ord>() -> (Sender<T>, Receiver<T>) {
+  let state = State { heap: BinaryHeap::new(), sent: 0, senders: 1, receivers: 1 };
+  let repr = Arc::new(Repr { state: Mutex::new(state), cond: Condvar::new() });
+  (Sender { repr: repr.clone() }, Receiver { repr })
+}
+
+impl<T: Ord> Sender<T> {
+  // Queues `msg`. Fails, handing `msg` back, if all receivers are gone.
+
+  pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+    let mut state = self.repr.state.lock().unwrap();
+    if state.receivers == 0 {
+      return Err(SendError(msg));
+    }
+    let seq = Reverse(state.sent);
+    state.sent += 1;
+    state.heap.push(Entry { msg, seq });
+    self.repr.cond.notify_one();
+    Ok(())
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    self.repr.state.lock().unwrap().senders += 1;
+    Sender { repr: self.repr.clone() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.repr.state.lock().unwrap_or_else(|e| e.into_inner());
+    state.senders -= 1;
+    if state.senders == 0 {
+      self.repr.cond.notify_all();
+    }
+  }
+}
+
+impl<T: Ord> Receiver<T> {
+  // Blocks until a message is available and returns the greatest one. Fails
+  // once no messages are left and all senders are gone.
+
+  pub fn recv(&self) -> Result<T, RecvError> {
+    let state = self.repr.state.lock().unwrap();
+    let mut state = self.repr.cond.wait_while(state, |s| s.heap.is_empty() && s.senders > 0).unwrap();
+    state.heap.pop().map(|e| e.msg).ok_or(RecvError::Disconnected)
+  }
+
+  pub fn try_recv(&self) -> Result<T, TryRecvError> {
+    let mut state = self.repr.state.lock().unwrap();
+    match state.heap.pop() {
+      Some(e) => Ok(e.msg),
+      None if state.senders == 0 => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.repr.state.lock().unwrap();
+    loop {
+      if let Some(e) = state.heap.pop() {
+        return Ok(e.msg);
+      }
+      if state.senders == 0 {
+        return Err(RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(RecvTimeoutError::Timeout);
+      }
+      state = self.repr.cond.wait_timeout(state, deadline - now).unwrap().0;
+    }
+  }
+
+  // The number of pending messages.
+
+  pub fn len(&self) -> usize {
+    self.repr.state.lock().unwrap().heap.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<T> Clone for Receiver<T> {
+  fn clone(&self) -> Self {
+    self.repr.state.lock().unwrap().receivers += 1;
+    Receiver { repr: self.repr.clone() }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.repr.state.lock().unwrap_or_else(|e| e.into_inner());
+    state.receivers -= 1;
+    if state.receivers == 0 {
+      // Drop the pending messages outside the lock.
+      let heap = std::mem::take(&mut state.heap);
+      drop(state);
+      drop(heap);
+    }
+  }
+}
+
+#[test]
+fn test_priority_order() {
+  let (s, r) = channel();
+  for (priority, job) in [(1, "low"), (5, "high"), (3, "mid"), (5, "high too")] {
+    s.send((priority, Reverse(job))).unwrap();
+  }
+  drop(s);
+  let jobs: Vec<_> = std::iter::from_fn(|| r.recv().ok()).map(|(_, Reverse(job))| job).collect();
+  assert_eq!(jobs, vec!["high", "high too", "mid", "low"]);
+}
+
+#[test]
+fn test_priority_equal_messages_in_send_order() {
+  // Orders only by the first field, so the second one tells them apart.
+  #[derive(Debug)]
+  struct Job(u8, usize);
+  impl Ord for Job {
+    fn cmp(&self, other: &Self) -> Ordering {
+      self.0.cmp(&other.0)
+    }
+  }
+  impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+      Some(self.cmp(other))
+    }
+  }
+  impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+      self.0 == other.0
+    }
+  }
+  impl Eq for Job {}
+
+  let (s, r) = channel();
+  for i in 0..10 {
+    s.send(Job((i % 2) as u8, i)).unwrap();
+  }
+  assert_eq!(r.len(), 10);
+  let order: Vec<_> = std::iter::from_fn(|| r.try_recv().ok()).map(|j| j.1).collect();
+  assert_eq!(order, vec![1, 3, 5, 7, 9, 0, 2, 4, 6, 8]);
+  assert_eq!(r.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+}
@@ -0,0 +1,95 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::oneshot::{new_chan, Recv};
+use crate::threads;
+
+/* Child processes as channel stages.
+
+`spawn_piped` starts a command with its standard input and output connected to
+channels, so an external tool can be used like any other stage: the chunks of
+bytes sent on `stdin` are written to the child, and what the child writes comes
+out of `stdout` in chunks as they are read. Closing the `stdin` sender closes
+the child's input.
+
+Each direction is pumped by a thread of its own, so the child never blocks on
+a full pipe just because the caller is busy with the other direction. Once the
+child has closed its output, the output thread waits for it to exit and sends
+the exit status on `status`. If the `stdout` receiver is dropped early, the
+child's output pipe is closed, and the child sees a broken pipe when it writes
+next. */
+
+pub struct Piped {
+  pub stdin: MultiSend<Vec<u8>>,
+  pub stdout: MultiRecv<Vec<u8>>,
+  pub status: Recv<io::Result<ExitStatus>>,
+}
+
+// The size of the chunks read from the child's output.
+const CHUNK: usize = 8192;
+
+// Sends what `reader` reads in chunks, until the end of the input, a read error,
+// or the receiver is gone. Returning closes `out`.
+
+fn pump_output(reader: &mut impl Read, mut out: MultiSend<Vec<u8>>) {
+  let mut buf = vec![0; CHUNK];
+  loop {
+    match reader.read(&mut buf) {
+      Ok(0) | Err(_) => return,
+      Ok(n) => {
+        out = match out.send(buf[..n].to_vec()) {
+          Ok(out) => out,
+          Err(_) => return,
+        }
+      }
+    }
+  }
+}
+
+pub fn spawn_piped(mut cmd: Command) -> io::Result<Piped> {
+  let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+  let mut child_in = child.stdin.take().unwrap();
+  let mut child_out = child.stdout.take().unwrap();
+
+  let (stdin, input) = new_multi_chan::<Vec<u8>>();
+  threads::spawn("process-stdin".to_string(), move || {
+    for chunk in input {
+      if child_in.write_all(&chunk).is_err() {
+        // The child closed its input; drop the rest.
+        break;
+      }
+    }
+    // Dropping `child_in` closes the child's input.
+  });
+
+  let (out, stdout) = new_multi_chan();
+  let (status_send, status) = new_chan();
+  threads::spawn("process-stdout".to_string(), move || {
+    pump_output(&mut child_out, out);
+    drop(child_out);
+    let _ = status_send.send(child.wait());
+  });
+
+  Ok(Piped { stdin, stdout, status })
+}
+
+#[cfg(unix)]
+#[test]
+fn test_spawn_piped_cat() {
+  let Piped { stdin, stdout, status } = spawn_piped(Command::new("cat")).unwrap();
+  stdin.send(b"hello ".to_vec()).unwrap().send(b"world".to_vec()).unwrap().close();
+  let output: Vec<u8> = stdout.into_iter().flatten().collect();
+  assert_eq!(output, b"hello world");
+  assert!(status.recv().unwrap().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_spawn_piped_exit_status() {
+  let mut cmd = Command::new("sh");
+  cmd.args(["-c", "echo out; exit 3"]);
+  let piped = spawn_piped(cmd).unwrap();
+  assert_eq!(piped.stdout.into_iter().flatten().collect::<Vec<u8>>(), b"out\n");
+  assert_eq!(piped.status.recv().unwrap().unwrap().code(), Some(3));
+}
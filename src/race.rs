@@ -0,0 +1,130 @@
+use crate::errors::TryRecvError;
+use crate::oneshot::{new_chan, Recv};
+use crate::select::Select;
+use crate::sync::CancelToken;
+use crate::threads;
+
+/* Racing concurrent attempts.
+
+To hedge a slow operation, start several attempts at once and take whichever
+answers first. `first_of` waits on several one-shot receivers and returns the
+first message to arrive, with the index of its receiver. `first_ok` runs each
+attempt on a thread of its own, with a one-shot channel for its result, and
+returns the first successful result. It then cancels the shared `CancelToken`
+so the attempts still running can stop early; it does not wait for them. */
+
+// The first message to arrive on any of `receivers`, with the index of its
+// receiver. Receivers whose sender is gone are skipped; returns `None` if all
+// of them are. The other receivers are dropped.
+
+pub fn first_of<T>(receivers: Vec<Recv<T>>) -> Option<(usize, T)> {
+  let mut pending: Vec<(usize, Recv<T>)> = receivers.into_iter().enumerate().collect();
+  while !pending.is_empty() {
+    let i = ready(&pending);
+    match pending[i].1.try_recv() {
+      Ok(msg) => return Some((pending[i].0, msg)),
+      Err(TryRecvError::Disconnected) => {
+        pending.remove(i);
+      }
+      Err(TryRecvError::Empty) => unreachable!("a ready one-shot receiver is never empty"),
+    }
+  }
+  None
+}
+
+// Runs every attempt on its own thread and returns the first successful
+// result. If all attempts fail, returns their errors in the order of
+// `attempts`. An attempt that panics counts as failed without an error.
+
+pub fn first_ok<T, E, F>(attempts: Vec<F>) -> Result<T, Vec<E>>
+where
+  T: Send + 'static,
+  E: Send + 'static,
+  F: FnOnce(CancelToken) -> Result<T, E> + Send + 'static,
+{
+  let token = CancelToken::new();
+  let receivers: Vec<_> = attempts.into_iter().enumerate().map(|(i, attempt)| {
+    let (s, r) = new_chan();
+    let token = token.clone();
+    threads::spawn(format!("first_ok-{}", i), move || {
+      // The race may be over already; then nobody wants the result.
+      let _ = s.send(attempt(token));
+    });
+    r
+  }).collect();
+
+  let mut pending: Vec<(usize, Recv<Result<T, E>>)> = receivers.into_iter().enumerate().collect();
+  let mut errors = Vec::new();
+  while !pending.is_empty() {
+    let i = ready(&pending);
+    match pending[i].1.try_recv() {
+      Ok(Ok(value)) => {
+        token.cancel();
+        return Ok(value);
+      }
+      Ok(Err(e)) => errors.push((pending.remove(i).0, e)),
+      // The attempt panicked.
+      Err(TryRecvError::Disconnected) => drop(pending.remove(i)),
+      Err(TryRecvError::Empty) => unreachable!("a ready one-shot receiver is never empty"),
+    }
+  }
+  errors.sort_by_key(|(i, _)| *i);
+  Err(errors.into_iter().map(|(_, e)| e).collect())
+}
+
+// Blocks until one of the receivers is ready and returns its position.
+
+fn ready<T>(pending: &[(usize, Recv<T>)]) -> usize {
+  let mut select = Select::new();
+  for (_, r) in pending {
+    select.add(r);
+  }
+  select.ready()
+}
+
+#[test]
+fn test_first_of() {
+  use std::thread;
+  use std::time::Duration;
+
+  let (s0, r0) = new_chan::<&str>();
+  let (s1, r1) = new_chan();
+  let (s2, r2) = new_chan();
+  drop(s0);
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(20));
+    // The race is over by now, so this fails.
+    assert!(s2.send("slow").is_err());
+  });
+  s1.send("fast").unwrap();
+  assert_eq!(first_of(vec![r0, r1, r2]), Some((1, "fast")));
+  h.join().unwrap();
+
+  let (s, r) = new_chan::<()>();
+  drop(s);
+  assert_eq!(first_of(vec![r]), None);
+}
+
+#[test]
+fn test_first_ok() {
+  use std::thread;
+  use std::time::Duration;
+
+  type Attempt = Box<dyn FnOnce(CancelToken) -> Result<u32, String> + Send>;
+  let attempts: Vec<Attempt> = vec![
+    Box::new(|token| {
+      // Would take 10 seconds if the race did not cancel it.
+      token.wait_timeout(Duration::from_secs(10));
+      Err("cancelled".to_string())
+    }),
+    Box::new(|_| Err("failed".to_string())),
+    Box::new(|_| {
+      thread::sleep(Duration::from_millis(20));
+      Ok(7)
+    }),
+  ];
+  assert_eq!(first_ok(attempts), Ok(7));
+
+  let failing: Vec<fn(CancelToken) -> Result<(), u32>> = vec![|_| Err(1), |_| panic!("attempt failed"), |_| Err(3)];
+  assert_eq!(first_ok(failing), Err(vec![1, 3]));
+}
@@ -0,0 +1,135 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::TryRecvError;
+use crate::multishot::MultiRecv;
+
+/* Throughput of a receiver.
+
+`rate_meter` wraps a multi-shot receiver in a `Metered` receiver that counts
+every message it hands out. A `RateGauge` reads the count as messages per
+second, averaged over roughly the last `window`, e.g. for autoscaling logic
+that only needs to know how busy a consumer is.
+
+The average is an exponentially decaying count: each message adds one, and the
+count decays by a factor `e` every `window`. At a steady rate `r` the count
+settles at `r * window`. There is no timer behind it; the decay is computed
+from the time between messages when one is counted, and from the time since the
+last message when the gauge is read, so an idle receiver reads as slowing down
+towards zero. Counting a message costs one uncontended lock. */
+
+struct Decaying {
+  count: f64,
+  last: Instant,
+}
+
+#[derive(Clone)]
+pub struct RateGauge {
+  window: f64,
+  state: Arc<Mutex<Decaying>>,
+}
+
+impl RateGauge {
+  fn new(window: Duration) -> RateGauge {
+    assert!(!window.is_zero(), "rate_meter: window must be positive");
+    let state = Decaying { count: 0.0, last: Instant::now() };
+    RateGauge { window: window.as_secs_f64(), state: Arc::new(Mutex::new(state)) }
+  }
+
+  fn decay(&self, st: &Decaying, now: Instant) -> f64 {
+    let dt = now.saturating_duration_since(st.last).as_secs_f64();
+    st.count * (-dt / self.window).exp()
+  }
+
+  fn record_at(&self, now: Instant) {
+    let mut st = self.state.lock().unwrap();
+    st.count = self.decay(&st, now) + 1.0;
+    st.last = now;
+  }
+
+  fn rate_at(&self, now: Instant) -> f64 {
+    let st = self.state.lock().unwrap();
+    self.decay(&st, now) / self.window
+  }
+
+  // Messages per second received recently.
+
+  pub fn rate(&self) -> f64 {
+    self.rate_at(Instant::now())
+  }
+}
+
+// A receiver that feeds a `RateGauge`.
+
+pub struct Metered<T> {
+  receiver: Option<MultiRecv<T>>,
+  gauge: RateGauge,
+}
+
+impl<T> MultiRecv<T> {
+  pub fn rate_meter(self, window: Duration) -> Metered<T> {
+    Metered { receiver: Some(self), gauge: RateGauge::new(window) }
+  }
+}
+
+impl<T> Metered<T> {
+  pub fn gauge(&self) -> RateGauge {
+    self.gauge.clone()
+  }
+
+  // Like `MultiRecv::recv`, but keeps the receiver in place. Returns `None`
+  // once the stream is closed.
+
+  pub fn recv(&mut self) -> Option<T> {
+    let (msg, next) = self.receiver.take()?.recv()?;
+    self.receiver = Some(next);
+    self.gauge.record_at(Instant::now());
+    Some(msg)
+  }
+
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let receiver = self.receiver.as_mut().ok_or(TryRecvError::Disconnected)?;
+    let msg = receiver.try_recv()?;
+    self.gauge.record_at(Instant::now());
+    Ok(msg)
+  }
+}
+
+impl<T> Iterator for Metered<T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.recv()
+  }
+}
+
+#[test]
+fn test_rate_gauge_decay() {
+  let gauge = RateGauge::new(Duration::from_secs(1));
+  let start = gauge.state.lock().unwrap().last;
+  // 100 messages per second for 10 seconds.
+  for i in 1..=1000 {
+    gauge.record_at(start + Duration::from_millis(10 * i));
+  }
+  let end = start + Duration::from_secs(10);
+  let rate = gauge.rate_at(end);
+  assert!((rate - 100.0).abs() < 2.0, "rate {}", rate);
+  // Idle for one window: down by a factor e.
+  let idle = gauge.rate_at(end + Duration::from_secs(1));
+  assert!((idle - rate / std::f64::consts::E).abs() < 0.5, "idle rate {}", idle);
+}
+
+#[test]
+fn test_metered_recv() {
+  use crate::multishot::new_multi_chan;
+
+  let (s, r) = new_multi_chan();
+  let mut r = r.rate_meter(Duration::from_secs(60));
+  let gauge = r.gauge();
+  assert_eq!(gauge.rate(), 0.0);
+  s.send(1).unwrap().send(2).unwrap().close();
+  assert_eq!(r.try_recv(), Ok(1));
+  assert_eq!(r.by_ref().collect::<Vec<_>>(), vec![2]);
+  // Two messages, counted within a fraction of the 60 second window.
+  assert!((gauge.rate() * 60.0 - 2.0).abs() < 0.01);
+}
@@ -0,0 +1,162 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::errors::{RecvError, SendError};
+
+/* Zero-capacity (rendezvous) channels.
+
+A rendezvous channel has no buffer: `send` hands its message directly to a
+receiving thread and only returns once that thread has taken it, and `recv`
+waits until a sender shows up. This gives strict hand-off between the two
+threads, e.g. for pipelines that must run in lock-step.
+
+The representation is a single slot behind a mutex, with counters of how many
+messages have been offered and taken, so that a sender can tell when its own
+message was taken. */
+
+struct Repr<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar,
+}
+
+struct State<T> {
+  slot: Option<T>,
+  offered: u64,
+  taken: u64,
+  senders: usize,
+  receiver_alive: bool,
+}
+
+// The sending end. It can be cloned to send from several threads; each
+// message is still handed over one at a time.
+
+pub struct SyncSend<T> {
+  repr: Arc<Repr<T>>,
+}
+
+pub struct SyncRecv<T> {
+  repr: Arc<Repr<T>>,
+}
+
+pub fn sync_chan<T>() -> (SyncSend<T>, SyncRecv<T>) {
+  let state = State { slot: None, offered: 0, taken: 0, senders: 1, receiver_alive: true };
+  let repr = Arc::new(Repr { state: Mutex::new(state), cond: Condvar::new() });
+  (SyncSend { repr: repr.clone() }, SyncRecv { repr })
+}
+
+impl<T> SyncSend<T> {
+  // Blocks until a receiver has taken `msg`. Fails, handing `msg` back, if the
+  // receiver is dropped before taking it.
+
+  pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+    let cond = &self.repr.cond;
+    let state = self.repr.state.lock().unwrap();
+    // Wait for messages of other senders to be taken first.
+    let mut state = cond.wait_while(state, |s| s.slot.is_some() && s.receiver_alive).unwrap();
+    if !state.receiver_alive {
+      return Err(SendError(msg));
+    }
+    state.slot = Some(msg);
+    state.offered += 1;
+    let ticket = state.offered;
+    cond.notify_all();
+
+    let mut state = cond.wait_while(state, |s| s.taken < ticket && s.receiver_alive).unwrap();
+    if state.taken >= ticket {
+      Ok(())
+    } else {
+      // The receiver went away; our message is still in the slot.
+      Err(SendError(state.slot.take().unwrap()))
+    }
+  }
+}
+
+impl<T> Clone for SyncSend<T> {
+  fn clone(&self) -> Self {
+    self.repr.state.lock().unwrap().senders += 1;
+    SyncSend { repr: self.repr.clone() }
+  }
+}
+
+impl<T> Drop for SyncSend<T> {
+  fn drop(&mut self) {
+    self.repr.state.lock().unwrap_or_else(|e| e.into_inner()).senders -= 1;
+    self.repr.cond.notify_all();
+  }
+}
+
+impl<T> SyncRecv<T> {
+  // Blocks until a sender offers a message. Fails once all senders are gone.
+
+  pub fn recv(&self) -> Result<T, RecvError> {
+    let state = self.repr.state.lock().unwrap();
+    let mut state = self.repr.cond.wait_while(state, |s| s.slot.is_none() && s.senders > 0).unwrap();
+    match state.slot.take() {
+      Some(msg) => {
+        state.taken += 1;
+        self.repr.cond.notify_all();
+        Ok(msg)
+      }
+      None => Err(RecvError::Disconnected),
+    }
+  }
+}
+
+impl<T> Drop for SyncRecv<T> {
+  fn drop(&mut self) {
+    self.repr.state.lock().unwrap_or_else(|e| e.into_inner()).receiver_alive = false;
+    self.repr.cond.notify_all();
+  }
+}
+
+#[test]
+fn test_sync_chan_handoff() {
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  let (s, r) = sync_chan();
+  let returned = Arc::new(AtomicBool::new(false));
+  let returned2 = returned.clone();
+  let h = thread::spawn(move || {
+    s.send(1).unwrap();
+    returned2.store(true, Ordering::SeqCst);
+  });
+
+  // The sender cannot return before the message is taken.
+  thread::sleep(Duration::from_millis(50));
+  assert!(!returned.load(Ordering::SeqCst));
+  assert_eq!(r.recv(), Ok(1));
+  h.join().unwrap();
+  assert!(returned.load(Ordering::SeqCst));
+  assert_eq!(r.recv(), Err(RecvError::Disconnected));
+}
+
+#[test]
+fn test_sync_chan_multiple_senders() {
+  use std::thread;
+
+  let (s, r) = sync_chan();
+  let handles: Vec<_> = (0..4).map(|i| {
+    let s = s.clone();
+    thread::spawn(move || s.send(i).unwrap())
+  }).collect();
+  drop(s);
+  let mut got: Vec<_> = (0..4).map(|_| r.recv().unwrap()).collect();
+  got.sort();
+  assert_eq!(got, vec![0, 1, 2, 3]);
+  for h in handles {
+    h.join().unwrap();
+  }
+}
+
+#[test]
+fn test_sync_chan_receiver_dropped() {
+  use std::thread;
+  use std::time::Duration;
+
+  let (s, r) = sync_chan();
+  let h = thread::spawn(move || s.send("hello"));
+  thread::sleep(Duration::from_millis(20));
+  drop(r);
+  assert_eq!(h.join().unwrap(), Err(SendError("hello")));
+}
@@ -0,0 +1,111 @@
+use crate::errors::SendError;
+use crate::multishot::{new_multi_chan, MultiRecv, SharedSend};
+use crate::oneshot::{new_chan, Recv, Send};
+
+/* Request/response channels.
+
+A request travels over a multi-shot channel together with the sender of a
+fresh one-shot channel, and the requester keeps the matching receiver. The
+responder answers by sending on that one-shot sender, so every response goes
+to the thread that made the request, however many threads make requests.
+
+If the responder drops a request without answering it, the requester's
+receiver sees the one-shot channel disconnect. */
+
+pub struct Requester<Req, Resp> {
+  sender: SharedSend<(Req, Send<Resp>)>,
+}
+
+pub struct Responder<Req, Resp> {
+  // `None` once the stream has ended.
+  receiver: Option<MultiRecv<(Req, Send<Resp>)>>,
+}
+
+pub fn channel<Req, Resp>() -> (Requester<Req, Resp>, Responder<Req, Resp>) {
+  let (s, r) = new_multi_chan();
+  (Requester { sender: s.into_shared() }, Responder { receiver: Some(r) })
+}
+
+impl<Req, Resp> Requester<Req, Resp> {
+  // Sends a request and returns the receiver for its response. Fails, handing
+  // `msg` back, if the responder is gone.
+
+  pub fn request(&self, msg: Req) -> Result<Recv<Resp>, SendError<Req>> {
+    let (reply, response) = new_chan();
+    match self.sender.send((msg, reply)) {
+      Ok(()) => Ok(response),
+      // The channel is unbounded, so this only fails once the responder is gone.
+      Err(e) => Err(SendError(e.into_inner().0)),
+    }
+  }
+}
+
+impl<Req, Resp> Clone for Requester<Req, Resp> {
+  fn clone(&self) -> Self {
+    Requester { sender: self.sender.clone() }
+  }
+}
+
+impl<Req, Resp> Responder<Req, Resp> {
+  // Blocks until the next request arrives, and returns it with the sender for
+  // its response. Returns `None` once all requesters are gone.
+
+  pub fn recv(&mut self) -> Option<(Req, Send<Resp>)> {
+    let (request, next) = self.receiver.take()?.recv()?;
+    self.receiver = Some(next);
+    Some(request)
+  }
+
+  // Answers every request with `f` until all requesters are gone. Responses to
+  // requesters that stopped waiting are dropped.
+
+  pub fn serve(mut self, mut f: impl FnMut(Req) -> Resp) {
+    while let Some((msg, reply)) = self.recv() {
+      let _ = reply.send(f(msg));
+    }
+  }
+}
+
+impl<Req, Resp> Iterator for Responder<Req, Resp> {
+  type Item = (Req, Send<Resp>);
+
+  fn next(&mut self) -> Option<(Req, Send<Resp>)> {
+    self.recv()
+  }
+}
+
+#[test]
+fn test_reqres_responses_go_to_requester() {
+  use std::thread;
+
+  let (requester, responder) = channel();
+  let server = thread::spawn(move || responder.serve(|n: u32| n * 2));
+  let clients: Vec<_> = (0..4).map(|i| {
+    let requester = requester.clone();
+    thread::spawn(move || {
+      let pending: Vec<_> = (0..10).map(|j| requester.request(i * 10 + j).unwrap()).collect();
+      pending.into_iter().map(|r| r.recv().unwrap()).collect::<Vec<_>>()
+    })
+  }).collect();
+  drop(requester);
+  for (i, c) in clients.into_iter().enumerate() {
+    let expected: Vec<_> = (0..10).map(|j| (i as u32 * 10 + j) * 2).collect();
+    assert_eq!(c.join().unwrap(), expected);
+  }
+  server.join().unwrap();
+}
+
+#[test]
+fn test_reqres_unanswered_and_closed() {
+  use crate::errors::RecvError;
+
+  let (requester, mut responder) = channel::<&str, ()>();
+  let response = requester.request("ignored").unwrap();
+  let (msg, reply) = responder.recv().unwrap();
+  assert_eq!(msg, "ignored");
+  drop(reply);
+  assert_eq!(response.recv(), Err(RecvError::Disconnected));
+
+  drop(responder);
+  assert!(matches!(requester.request("late"), Err(SendError("late"))));
+}
@@ -0,0 +1,31 @@
+use std::hash::{BuildHasher, RandomState};
+
+/* A small pseudo-random number generator for randomized policies, such as
+picking among ready receivers or deciding which messages to shed. It is not
+suitable for anything that needs good or secure randomness. */
+
+#[derive(Clone, Copy)]
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+  // Seeded from the randomly keyed std hasher; xorshift needs a non-zero seed.
+
+  pub(crate) fn new() -> XorShift {
+    XorShift(RandomState::new().hash_one(0u8) | 1)
+  }
+
+  pub(crate) fn next_u64(&mut self) -> u64 {
+    let mut x = self.0;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    self.0 = x;
+    x
+  }
+
+  // A number in `[0, 1)`.
+
+  pub(crate) fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
@@ -0,0 +1,348 @@
+use std::cell::Cell;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::{SendError, TrySendError};
+use crate::multishot::MultiSend;
+use crate::rng::XorShift;
+
+/* Waiting on several receivers at once.
+
+A `Select` holds a list of receivers and blocks until one of them is ready,
+that is, until its `try_recv` would not return `Empty`. It returns the index
+of that receiver, and the caller then receives from it. A receiver whose
+sender is gone counts as ready, so a closed channel is reported rather than
+waited on forever.
+
+Each channel can only notify its own `Condvar`, so `Select` cannot wait on
+them directly. Instead, it gives every receiver a shared `Signal` while it
+waits; the channels notify the signals of their watchers whenever they send a
+message or their sender goes away, and `Select` waits on its signal.
+
+When several receivers are ready, the `SelectPolicy` decides which one is
+returned. The default gives priority to the receiver added first, which can
+starve the others while it stays busy; `RoundRobin` and `Random` share the
+turns between them. */
+
+// A flag that channels set to wake up a waiting `Select`.
+
+pub struct Signal {
+  notified: Mutex<bool>,
+  cond: Condvar,
+}
+
+impl Signal {
+  fn new() -> Signal {
+    Signal { notified: Mutex::new(false), cond: Condvar::new() }
+  }
+
+  pub(crate) fn notify(&self) {
+    *self.notified.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    self.cond.notify_all();
+  }
+
+  fn reset(&self) {
+    *self.notified.lock().unwrap() = false;
+  }
+
+  // Waits until notified or until the deadline has passed.
+
+  fn wait(&self, deadline: Option<Instant>) {
+    let notified = self.notified.lock().unwrap();
+    match deadline {
+      None => drop(self.cond.wait_while(notified, |n| !*n).unwrap()),
+      Some(deadline) => {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        drop(self.cond.wait_timeout_while(notified, timeout, |n| !*n).unwrap());
+      }
+    }
+  }
+}
+
+// Receivers that can be used with `Select`.
+
+pub trait Selectable {
+  // Whether receiving would not block.
+  fn select_ready(&self) -> bool;
+  // Registers `signal` to be notified when the receiver may have become ready.
+  fn watch(&self, signal: &Arc<Signal>);
+  fn unwatch(&self, signal: &Arc<Signal>);
+}
+
+// Which receiver `Select` returns when several are ready.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SelectPolicy {
+  // The one that was added first.
+  #[default]
+  Priority,
+  // The first one after the receiver returned last time, wrapping around.
+  RoundRobin,
+  // A random one.
+  Random,
+}
+
+pub struct Select<'a> {
+  receivers: Vec<&'a dyn Selectable>,
+  policy: SelectPolicy,
+  // Where the next round-robin scan starts.
+  next: Cell<usize>,
+  // For `Random`.
+  rng: Cell<XorShift>,
+}
+
+impl Default for Select<'_> {
+  fn default() -> Self {
+    Select::new()
+  }
+}
+
+impl<'a> Select<'a> {
+  pub fn new() -> Select<'a> {
+    Select::with_policy(SelectPolicy::Priority)
+  }
+
+  pub fn with_policy(policy: SelectPolicy) -> Select<'a> {
+    Select { receivers: Vec::new(), policy, next: Cell::new(0), rng: Cell::new(XorShift::new()) }
+  }
+
+  // Adds a receiver and returns its index.
+
+  pub fn add(&mut self, receiver: &'a dyn Selectable) -> usize {
+    self.receivers.push(receiver);
+    self.receivers.len() - 1
+  }
+
+  // The index of a ready receiver, if there is one, without blocking.
+
+  pub fn try_ready(&self) -> Option<usize> {
+    let n = self.receivers.len();
+    match self.policy {
+      SelectPolicy::Priority => self.receivers.iter().position(|r| r.select_ready()),
+      SelectPolicy::RoundRobin => {
+        let start = self.next.get();
+        let i = (start..start + n).map(|i| i % n).find(|&i| self.receivers[i].select_ready())?;
+        self.next.set(i + 1);
+        Some(i)
+      }
+      SelectPolicy::Random => {
+        let ready: Vec<_> = (0..n).filter(|&i| self.receivers[i].select_ready()).collect();
+        if ready.is_empty() {
+          return None;
+        }
+        Some(ready[self.random() as usize % ready.len()])
+      }
+    }
+  }
+
+  fn random(&self) -> u64 {
+    let mut rng = self.rng.get();
+    let x = rng.next_u64();
+    self.rng.set(rng);
+    x
+  }
+
+  // Blocks until a receiver is ready and returns its index. Panics if there
+  // are no receivers, as it would block forever.
+
+  pub fn ready(&self) -> usize {
+    assert!(!self.receivers.is_empty(), "Select::ready: no receivers to wait for");
+    self.wait(None).unwrap()
+  }
+
+  // Like `ready`, but gives up after `timeout`.
+
+  pub fn ready_timeout(&self, timeout: Duration) -> Option<usize> {
+    self.wait(Some(Instant::now() + timeout))
+  }
+
+  fn wait(&self, deadline: Option<Instant>) -> Option<usize> {
+    if let Some(i) = self.try_ready() {
+      return Some(i);
+    }
+    let signal = Arc::new(Signal::new());
+    for r in &self.receivers {
+      r.watch(&signal);
+    }
+    let ready = loop {
+      // Reset before checking, so a notification that arrives after the check
+      // is not lost.
+      signal.reset();
+      if let Some(i) = self.try_ready() {
+        break Some(i);
+      }
+      if deadline.is_some_and(|d| Instant::now() >= d) {
+        break None;
+      }
+      signal.wait(deadline);
+    };
+    for r in &self.receivers {
+      r.unwatch(&signal);
+    }
+    ready
+  }
+}
+
+/* Waiting to send.
+
+`select_send` is the counterpart of `Select` for senders: it sends a message
+on the first of several senders whose channel has room, waiting until one has,
+e.g. to route work to whichever worker is not behind. An unbounded channel
+always has room. A sender whose receiver is gone is skipped, and if all of
+them are, the message is handed back.
+
+It waits the same way as `Select`, on a `Signal` that the bounded channels
+notify whenever room is freed or their capacity changes. The room is claimed
+with a permit, as in `MultiSend::try_reserve`, so the message goes to exactly
+one channel and never runs into its overflow policy. */
+
+// Sends `msg` on the first of `senders` that has room, waiting until one has,
+// and returns its index.
+
+pub fn select_send<T>(senders: &mut [&mut MultiSend<T>], msg: T) -> Result<usize, SendError<T>> {
+  let mut msg = match try_select_send(senders, msg) {
+    Err(TrySendError::Full(msg)) => msg,
+    result => return result.map_err(|e| SendError(e.into_inner())),
+  };
+  let signal = Arc::new(Signal::new());
+  let bounds: Vec<_> = senders.iter().filter_map(|s| s.bound().cloned()).collect();
+  for b in &bounds {
+    b.watch(&signal);
+  }
+  let result = loop {
+    // Reset before trying, so room that is freed after the try is not missed.
+    signal.reset();
+    match try_select_send(senders, msg) {
+      Err(TrySendError::Full(m)) => msg = m,
+      result => break result,
+    }
+    signal.wait(None);
+  };
+  for b in &bounds {
+    b.unwatch(&signal);
+  }
+  result.map_err(|e| SendError(e.into_inner()))
+}
+
+// Like `select_send`, but returns `TrySendError::Full` instead of waiting.
+
+pub fn try_select_send<T>(senders: &mut [&mut MultiSend<T>], msg: T) -> Result<usize, TrySendError<T>> {
+  let mut msg = msg;
+  for (i, s) in senders.iter_mut().enumerate() {
+    if s.is_closed() {
+      continue;
+    }
+    if let Some(permit) = s.try_reserve() {
+      match permit.send(msg) {
+        Ok(()) => return Ok(i),
+        // The receiver went away in the meantime.
+        Err(SendError(m)) => msg = m,
+      }
+    }
+  }
+  if senders.iter().all(|s| s.is_closed()) {
+    Err(TrySendError::Disconnected(msg))
+  } else {
+    Err(TrySendError::Full(msg))
+  }
+}
+
+#[test]
+fn test_select_oneshot_and_multishot() {
+  use std::thread;
+
+  use crate::errors::TryRecvError;
+  use crate::multishot::new_multi_chan;
+  use crate::oneshot::new_chan;
+
+  let (s1, r1) = new_chan::<i32>();
+  let (s2, mut r2) = new_multi_chan();
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(20));
+    let s2 = s2.send("hello").unwrap();
+    thread::sleep(Duration::from_millis(20));
+    s1.send(7).unwrap();
+    s2
+  });
+
+  let mut sel = Select::new();
+  let a = sel.add(&r1);
+  let b = sel.add(&r2);
+  assert_eq!(sel.ready(), b);
+  drop(sel);
+  assert_eq!(r2.try_recv(), Ok("hello"));
+
+  let mut sel = Select::new();
+  sel.add(&r1);
+  sel.add(&r2);
+  assert_eq!(sel.ready(), a);
+  assert_eq!(r1.try_recv(), Ok(7));
+
+  // A closed channel counts as ready.
+  h.join().unwrap().close();
+  assert_eq!(sel.try_ready(), Some(a));
+  assert_eq!(r1.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_select_timeout() {
+  use crate::oneshot::new_chan;
+
+  let (_s1, r1) = new_chan::<i32>();
+  let (_s2, r2) = new_chan::<i32>();
+  let mut sel = Select::new();
+  sel.add(&r1);
+  sel.add(&r2);
+  assert_eq!(sel.ready_timeout(Duration::from_millis(20)), None);
+}
+
+#[test]
+fn test_select_policies() {
+  use crate::oneshot::new_chan;
+
+  // Three receivers that stay ready, as their senders are gone.
+  let receivers: Vec<_> = (0..3).map(|_| new_chan::<i32>().1).collect();
+  let picks = |policy| {
+    let mut sel = Select::with_policy(policy);
+    for r in &receivers {
+      sel.add(r);
+    }
+    (0..300).map(|_| sel.ready()).collect::<Vec<_>>()
+  };
+
+  assert!(picks(SelectPolicy::Priority).iter().all(|&i| i == 0));
+  let rr = picks(SelectPolicy::RoundRobin);
+  assert_eq!(rr[..6], [0, 1, 2, 0, 1, 2]);
+  let random = picks(SelectPolicy::Random);
+  for i in 0..3 {
+    // Each is picked about 100 times.
+    assert!(random.iter().filter(|&&j| j == i).count() > 50);
+  }
+}
+
+#[test]
+fn test_select_send() {
+  use std::thread;
+
+  use crate::multishot::new_bounded_multi_chan;
+
+  let (mut s1, mut r1) = new_bounded_multi_chan(1);
+  let (mut s2, mut r2) = new_bounded_multi_chan(1);
+  s1.try_send(0).unwrap();
+  // Only the second channel has room.
+  assert_eq!(select_send(&mut [&mut s1, &mut s2], 1), Ok(1));
+  assert_eq!(try_select_send(&mut [&mut s1, &mut s2], 2), Err(TrySendError::Full(2)));
+
+  // Both are full until the first one is read from.
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(r1.try_recv(), Ok(0));
+    r1
+  });
+  assert_eq!(select_send(&mut [&mut s1, &mut s2], 3), Ok(0));
+  let mut r1 = h.join().unwrap();
+  assert_eq!((r1.try_recv(), r2.try_recv()), (Ok(3), Ok(1)));
+
+  drop((r1, r2));
+  assert_eq!(select_send(&mut [&mut s1, &mut s2], 4), Err(SendError(4)));
+}
@@ -0,0 +1,173 @@
+use std::time::{Duration, Instant};
+
+use crate::errors::SendError;
+use crate::multishot::{new_multi_chan, MultiRecv, SharedSend};
+use crate::rng::XorShift;
+
+/* Shedding load to keep latency near a target.
+
+Under overload, a queue grows until every message waits longer than it is
+worth. A channel created with `by_latency(target)` stamps every message when
+it is sent, and the receiver measures how long each one waited in the queue. It
+keeps a moving average of these waits, and while the average is above
+`target`, it drops messages at random instead of delivering them: with an
+average wait of `avg`, a message is dropped with probability
+`1 - target / avg`. The queue then drains faster than it fills, which brings
+the wait back down, and shedding stops again.
+
+Dropped messages can be sent to a dead-letter channel instead of being
+discarded. The receiver counts the messages it received and shed. */
+
+pub struct ShedSend<T> {
+  sender: SharedSend<(Instant, T)>,
+}
+
+pub struct ShedRecv<T> {
+  // `None` once the stream has ended.
+  receiver: Option<MultiRecv<(Instant, T)>>,
+  target: Duration,
+  // Moving average of the queue wait, in seconds.
+  avg_wait: Option<f64>,
+  rng: XorShift,
+  stats: ShedStats,
+  dead_letters: Option<SharedSend<T>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShedStats {
+  // Messages taken from the queue, whether delivered or shed.
+  pub received: u64,
+  pub shed: u64,
+}
+
+impl ShedStats {
+  // The fraction of received messages that were shed.
+
+  pub fn shed_rate(&self) -> f64 {
+    if self.received == 0 {
+      0.0
+    } else {
+      self.shed as f64 / self.received as f64
+    }
+  }
+}
+
+// The weight of a new sample in the moving average of the wait.
+const SMOOTHING: f64 = 0.125;
+
+pub fn by_latency<T>(target: Duration) -> (ShedSend<T>, ShedRecv<T>) {
+  let (s, r) = new_multi_chan();
+  let receiver = ShedRecv {
+    receiver: Some(r),
+    target,
+    avg_wait: None,
+    rng: XorShift::new(),
+    stats: ShedStats::default(),
+    dead_letters: None,
+  };
+  (ShedSend { sender: s.into_shared() }, receiver)
+}
+
+impl<T> ShedSend<T> {
+  pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+    // The channel is unbounded, so this only fails once the receiver is gone.
+    self.sender.send((Instant::now(), msg)).map_err(|e| SendError(e.into_inner().1))
+  }
+}
+
+impl<T> Clone for ShedSend<T> {
+  fn clone(&self) -> Self {
+    ShedSend { sender: self.sender.clone() }
+  }
+}
+
+impl<T> ShedRecv<T> {
+  // Sends shed messages to `sink` instead of dropping them.
+
+  pub fn with_dead_letters(mut self, sink: SharedSend<T>) -> ShedRecv<T> {
+    self.dead_letters = Some(sink);
+    self
+  }
+
+  pub fn stats(&self) -> ShedStats {
+    self.stats
+  }
+
+  // The moving average of the time messages waited in the queue.
+
+  pub fn avg_wait(&self) -> Duration {
+    Duration::from_secs_f64(self.avg_wait.unwrap_or(0.0))
+  }
+
+  // Blocks until a message is delivered, shedding the ones in between. Returns
+  // `None` once the sender is gone and the queue is empty.
+
+  pub fn recv(&mut self) -> Option<T> {
+    loop {
+      let ((sent, msg), next) = self.receiver.take()?.recv()?;
+      self.receiver = Some(next);
+      self.stats.received += 1;
+
+      let wait = sent.elapsed().as_secs_f64();
+      let avg = match self.avg_wait {
+        Some(avg) => avg + SMOOTHING * (wait - avg),
+        None => wait,
+      };
+      self.avg_wait = Some(avg);
+
+      let target = self.target.as_secs_f64();
+      let drop_probability = if avg > target { 1.0 - target / avg } else { 0.0 };
+      if self.rng.next_f64() >= drop_probability {
+        return Some(msg);
+      }
+      self.stats.shed += 1;
+      if let Some(sink) = &self.dead_letters {
+        // Without a dead-letter receiver, the message is dropped after all.
+        let _ = sink.send(msg);
+      }
+    }
+  }
+}
+
+impl<T> Iterator for ShedRecv<T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.recv()
+  }
+}
+
+#[test]
+fn test_shed_under_overload() {
+  use std::thread;
+
+  let (s, r) = by_latency(Duration::from_millis(1));
+  let (dead, dead_letters) = new_multi_chan();
+  let mut r = r.with_dead_letters(dead.into_shared());
+  for i in 0..100 {
+    s.send(i).unwrap();
+  }
+  drop(s);
+  // Let the messages wait far beyond the target.
+  thread::sleep(Duration::from_millis(30));
+
+  let delivered = r.by_ref().count();
+  let stats = r.stats();
+  assert_eq!(stats.received, 100);
+  assert_eq!(delivered as u64 + stats.shed, 100);
+  assert!(stats.shed_rate() > 0.5);
+  assert!(r.avg_wait() >= Duration::from_millis(30));
+  drop(r);
+  assert_eq!(dead_letters.into_iter().count() as u64, stats.shed);
+}
+
+#[test]
+fn test_shed_nothing_below_target() {
+  let (s, mut r) = by_latency(Duration::from_secs(10));
+  for i in 0..100 {
+    s.send(i).unwrap();
+  }
+  drop(s);
+  assert_eq!(r.by_ref().collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+  assert_eq!(r.stats(), ShedStats { received: 100, shed: 0 });
+}
@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+use crate::multishot::{new_bounded_multi_chan_with_policy, MultiRecv, MultiSend, OverflowPolicy};
+use crate::sync::CancelToken;
+use crate::threads;
+
+/* OS signals as messages.
+
+`subscribe` turns the signals the process receives into messages on a
+multi-shot channel, and `cancel_on` cancels a `CancelToken` when one of them
+arrives, so that the threads watching the token stop on Ctrl-C the same way
+they stop on any other request to shut down.
+
+A signal handler may only make a few async-signal-safe calls, so the handler
+installed here only writes the signal number into a pipe. The first
+subscription starts a thread that reads the pipe and does the rest: it sends
+the signal to the subscribers that asked for it and cancels the tokens
+registered for it.
+
+The handler stays installed for the rest of the process, so once a signal has
+been subscribed to, it no longer terminates the process, even after every
+subscriber is gone. As with `threads::events`, a subscriber that does not keep
+up only keeps the latest `SIGNAL_BACKLOG` signals, and dropping the receiver
+unsubscribes it.
+
+The standard library links the C library on every Unix platform, so the few
+functions needed are declared here instead of coming from a crate. */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Signal {
+  // SIGHUP, sent when the terminal goes away.
+  Hangup,
+  // SIGINT, sent by Ctrl-C.
+  Interrupt,
+  // SIGTERM, sent by `kill` by default.
+  Terminate,
+}
+
+impl Signal {
+  // The same on all Unix platforms.
+
+  fn number(self) -> c_int {
+    match self {
+      Signal::Hangup => 1,
+      Signal::Interrupt => 2,
+      Signal::Terminate => 15,
+    }
+  }
+
+  fn from_number(n: u8) -> Option<Signal> {
+    [Signal::Hangup, Signal::Interrupt, Signal::Terminate].into_iter().find(|s| s.number() == c_int::from(n))
+  }
+}
+
+// How many unread signals a subscriber keeps.
+
+pub const SIGNAL_BACKLOG: usize = 64;
+
+extern "C" {
+  fn pipe(fds: *mut c_int) -> c_int;
+  fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+  fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+// What `signal` returns on failure.
+
+const SIG_ERR: usize = usize::MAX;
+
+// The writing end of the pipe, once it has been created.
+
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+struct Registry {
+  // The signals the handler is installed for.
+  installed: Vec<Signal>,
+  subscribers: Vec<(Vec<Signal>, MultiSend<Signal>)>,
+  tokens: Vec<(Vec<Signal>, CancelToken)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { installed: Vec::new(), subscribers: Vec::new(), tokens: Vec::new() });
+
+extern "C" fn on_signal(signum: c_int) {
+  let byte = signum as u8;
+  // Safety: `write` is async-signal-safe, and `byte` outlives the call. There
+  // is nothing to do about a failure here.
+  unsafe { write(PIPE.load(Ordering::Relaxed), &byte as *const u8 as *const c_void, 1) };
+}
+
+// Creates the pipe and its reader thread if that has not been done yet, and
+// installs the handler for `signals`.
+
+fn install(registry: &mut Registry, signals: &[Signal]) -> io::Result<()> {
+  if PIPE.load(Ordering::Relaxed) < 0 {
+    let mut fds = [0; 2];
+    // Safety: `fds` has room for the two descriptors `pipe` writes.
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    // Safety: the reading end was just created and belongs to nothing else.
+    let reader = unsafe { File::from_raw_fd(fds[0]) };
+    PIPE.store(fds[1], Ordering::Relaxed);
+    threads::spawn("signals".to_string(), move || dispatch(reader));
+  }
+  for &sig in signals {
+    if !registry.installed.contains(&sig) {
+      // Safety: `on_signal` only makes async-signal-safe calls.
+      if unsafe { signal(sig.number(), on_signal) } == SIG_ERR {
+        return Err(io::Error::last_os_error());
+      }
+      registry.installed.push(sig);
+    }
+  }
+  Ok(())
+}
+
+// Reads the signals the handler writes into the pipe and passes them on.
+
+fn dispatch(mut reader: File) {
+  let mut buf = [0; 64];
+  loop {
+    let n = match reader.read(&mut buf) {
+      Ok(0) => return,
+      Ok(n) => n,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(_) => return,
+    };
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    for sig in buf[..n].iter().filter_map(|&b| Signal::from_number(b)) {
+      // Sending only fails once the receiver is gone, so drop those subscribers.
+      registry.subscribers.retain_mut(|(signals, s)| !signals.contains(&sig) || s.try_send(sig).is_ok());
+      for (signals, token) in &registry.tokens {
+        if signals.contains(&sig) {
+          token.cancel();
+        }
+      }
+      registry.tokens.retain(|(_, token)| !token.is_cancelled());
+    }
+  }
+}
+
+fn register(signals: &[Signal], f: impl FnOnce(&mut Registry)) {
+  let mut registry = REGISTRY.lock().unwrap();
+  if let Err(e) = install(&mut registry, signals) {
+    panic!("signals: cannot install the signal handler: {}", e);
+  }
+  f(&mut registry)
+}
+
+// Subscribes to `signals`. The receiver gets each of them that the process
+// receives from now on.
+
+pub fn subscribe(signals: &[Signal]) -> MultiRecv<Signal> {
+  let (s, r) = new_bounded_multi_chan_with_policy(SIGNAL_BACKLOG, OverflowPolicy::DropOldest);
+  register(signals, |registry| registry.subscribers.push((signals.to_vec(), s)));
+  r
+}
+
+// Cancels `token` as soon as the process receives one of `signals`.
+
+pub fn cancel_on(signals: &[Signal], token: CancelToken) {
+  register(signals, |registry| {
+    registry.tokens.retain(|(_, token)| !token.is_cancelled());
+    registry.tokens.push((signals.to_vec(), token));
+  });
+}
+
+#[test]
+fn test_signals_delivered() {
+  use std::time::Duration;
+
+  use crate::errors::TryRecvError;
+
+  extern "C" {
+    fn raise(sig: c_int) -> c_int;
+  }
+
+  let hangup = subscribe(&[Signal::Hangup]);
+  let both = subscribe(&[Signal::Terminate, Signal::Hangup]);
+  let token = CancelToken::new();
+  cancel_on(&[Signal::Terminate], token.clone());
+
+  unsafe { raise(Signal::Terminate.number()) };
+  assert!(token.wait_timeout(Duration::from_secs(5)));
+  unsafe { raise(Signal::Hangup.number()) };
+
+  // The signals arrive in order, and only where they were asked for.
+  assert_eq!(both.into_iter().take(2).collect::<Vec<_>>(), vec![Signal::Terminate, Signal::Hangup]);
+  let (first, mut rest) = hangup.recv().unwrap();
+  assert_eq!(first, Signal::Hangup);
+  assert_eq!(rest.try_recv(), Err(TryRecvError::Empty));
+}
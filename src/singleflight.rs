@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::oneshot::{new_chan, Send};
+
+/* Collapsing concurrent identical requests.
+
+When several threads make the same request at the same time, e.g. ask a
+service thread for the same key over a channel, a `Group` lets only the first
+one through. The others wait for its response instead of sending requests of
+their own, and each gets a clone of it over its own one-shot channel. Unlike
+`cache::Memo`, nothing is kept once the response is delivered: a request made
+afterwards goes through again.
+
+If the request in flight panics, the waiting threads are woken up with their
+channels disconnected, and they try again. */
+
+pub struct Group<K, T> {
+  // The senders of the threads waiting for each request in flight.
+  in_flight: Mutex<HashMap<K, Vec<Send<T>>>>,
+}
+
+// Removes the entry of a request that panicked, which wakes up its waiters.
+
+struct InFlight<'a, K: Hash + Eq, T> {
+  group: &'a Group<K, T>,
+  key: &'a K,
+}
+
+impl<K: Hash + Eq, T> Drop for InFlight<'_, K, T> {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.group.in_flight.lock().unwrap_or_else(|e| e.into_inner()).remove(self.key);
+    }
+  }
+}
+
+impl<K: Hash + Eq + Clone, T: Clone> Default for Group<K, T> {
+  fn default() -> Self {
+    Group::new()
+  }
+}
+
+impl<K: Hash + Eq + Clone, T: Clone> Group<K, T> {
+  pub fn new() -> Group<K, T> {
+    Group { in_flight: Mutex::new(HashMap::new()) }
+  }
+
+  // Runs `f` for `key`, unless a request for `key` is already in flight, in
+  // which case it waits for that request's response instead.
+
+  pub fn call(&self, key: K, f: impl FnOnce() -> T) -> T {
+    let mut f = Some(f);
+    loop {
+      let mut in_flight = self.in_flight.lock().unwrap();
+      if let Some(waiters) = in_flight.get_mut(&key) {
+        let (s, r) = new_chan();
+        waiters.push(s);
+        drop(in_flight);
+        match r.recv() {
+          Ok(response) => return response,
+          // The request panicked; try again.
+          Err(_) => continue,
+        }
+      }
+
+      in_flight.insert(key.clone(), Vec::new());
+      drop(in_flight);
+      let guard = InFlight { group: self, key: &key };
+      // `f` is only taken here, and we return right after.
+      let response = (f.take().unwrap())();
+      drop(guard);
+
+      let waiters = self.in_flight.lock().unwrap().remove(&key).unwrap_or_default();
+      for w in waiters {
+        // A waiter cannot go away while waiting, but ignore it if it did.
+        let _ = w.send(response.clone());
+      }
+      return response;
+    }
+  }
+}
+
+#[test]
+fn test_singleflight_collapses_requests() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Barrier};
+  use std::time::Duration;
+
+  use crate::mpmc;
+
+  // A service thread answering requests sent over a channel.
+  let (requests, incoming) = mpmc::channel::<(u32, Send<u32>)>();
+  let served = Arc::new(AtomicUsize::new(0));
+  let served2 = served.clone();
+  let service = thread::spawn(move || {
+    for (n, reply) in incoming.iter() {
+      served2.fetch_add(1, Ordering::SeqCst);
+      thread::sleep(Duration::from_millis(50));
+      reply.send(n * n).unwrap();
+    }
+  });
+
+  let group = Arc::new(Group::new());
+  let barrier = Arc::new(Barrier::new(8));
+  let handles: Vec<_> = (0..8).map(|_| {
+    let group = group.clone();
+    let requests = requests.clone();
+    let barrier = barrier.clone();
+    thread::spawn(move || {
+      barrier.wait();
+      group.call(3, || {
+        let (s, r) = new_chan();
+        requests.send((3, s)).unwrap();
+        r.recv().unwrap()
+      })
+    })
+  }).collect();
+  for h in handles {
+    assert_eq!(h.join().unwrap(), 9);
+  }
+  assert_eq!(served.load(Ordering::SeqCst), 1);
+
+  // Nothing is cached: a later request goes through again.
+  assert_eq!(group.call(3, || 0), 0);
+  drop(requests);
+  service.join().unwrap();
+}
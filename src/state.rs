@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+
+use crate::multishot::{new_bounded_multi_chan_with_policy, MultiRecv, MultiSend, OverflowPolicy};
+
+/* Shared state with change notifications.
+
+A `Cell<T>` is a value behind a mutex that any thread can read and update.
+Every `update` also sends the value from before and after the update to every
+subscriber, so other components can react to a change without polling the
+cell. The diffs are sent while the lock is held, so every subscriber sees the
+updates in the order they were made.
+
+As with `threads::events`, a subscriber that does not keep up only keeps the
+latest `DIFF_BACKLOG` diffs; older ones are dropped, so a receiver that is never
+read holds on to a bounded amount of memory. Dropping the receiver unsubscribes
+it. */
+
+// How many unread diffs a subscriber keeps.
+
+pub const DIFF_BACKLOG: usize = 1024;
+
+pub struct Cell<T> {
+  inner: Mutex<Inner<T>>,
+}
+
+struct Inner<T> {
+  value: T,
+  subscribers: Vec<MultiSend<(T, T)>>,
+}
+
+impl<T: Clone> Cell<T> {
+  pub fn new(value: T) -> Cell<T> {
+    Cell { inner: Mutex::new(Inner { value, subscribers: Vec::new() }) }
+  }
+
+  // A copy of the current value.
+
+  pub fn get(&self) -> T {
+    self.inner.lock().unwrap().value.clone()
+  }
+
+  // Applies `f` to the value and sends `(old, new)` to the subscribers. The
+  // diff is sent even when `f` leaves the value unchanged.
+
+  pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    let mut inner = self.inner.lock().unwrap();
+    let old = inner.value.clone();
+    let result = f(&mut inner.value);
+    let new = inner.value.clone();
+    // Sending only fails once the receiver is gone, so drop those subscribers.
+    inner.subscribers.retain_mut(|s| s.try_send((old.clone(), new.clone())).is_ok());
+    result
+  }
+
+  // Subscribes to the updates made from now on.
+
+  pub fn subscribe(&self) -> MultiRecv<(T, T)> {
+    let (s, r) = new_bounded_multi_chan_with_policy(DIFF_BACKLOG, OverflowPolicy::DropOldest);
+    self.inner.lock().unwrap().subscribers.push(s);
+    r
+  }
+}
+
+impl<T: Clone + Default> Default for Cell<T> {
+  fn default() -> Self {
+    Cell::new(T::default())
+  }
+}
+
+#[test]
+fn test_cell_update_notifies_subscribers() {
+  let cell = Cell::new(1);
+  cell.update(|v| *v += 1);
+  let mut a = cell.subscribe();
+  let b = cell.subscribe();
+  assert_eq!(cell.update(|v| { *v *= 10; *v }), 20);
+  drop(b);
+  cell.update(|v| *v -= 5);
+  assert_eq!(cell.get(), 15);
+  assert_eq!(a.try_iter().collect::<Vec<_>>(), vec![(2, 20), (20, 15)]);
+}
+
+#[test]
+fn test_cell_across_threads() {
+  use std::sync::Arc;
+  use std::thread;
+
+  let cell = Arc::new(Cell::new(0));
+  let diffs = cell.subscribe();
+  let handles: Vec<_> = (0..4).map(|_| {
+    let cell = cell.clone();
+    thread::spawn(move || {
+      for _ in 0..100 {
+        cell.update(|v| *v += 1);
+      }
+    })
+  }).collect();
+  for h in handles {
+    h.join().unwrap();
+  }
+  assert_eq!(cell.get(), 400);
+  drop(cell);
+  // Every update is seen, in order.
+  let diffs: Vec<_> = diffs.into_iter().collect();
+  assert_eq!(diffs.len(), 400);
+  assert!(diffs.iter().enumerate().all(|(i, &(old, new))| old == i && new == i + 1));
+}
+
+#[test]
+fn test_unread_subscriber_is_bounded() {
+  let cell = Cell::new(0);
+  let mut diffs = cell.subscribe();
+  for _ in 0..2 * DIFF_BACKLOG {
+    cell.update(|v| *v += 1);
+  }
+  // Only the latest diffs are kept.
+  let kept: Vec<_> = diffs.try_iter().collect();
+  assert_eq!(kept.len(), DIFF_BACKLOG);
+  assert_eq!(kept.first(), Some(&(DIFF_BACKLOG, DIFF_BACKLOG + 1)));
+  assert_eq!(kept.last(), Some(&(2 * DIFF_BACKLOG - 1, 2 * DIFF_BACKLOG)));
+}
@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+
+use crate::errors::TrySendError;
+use crate::multishot::MultiSend;
+
+/* Redirecting producers to a new channel while they keep sending.
+
+A `Switch` is a cloneable sender, like a `SharedSend`, whose target can be
+replaced. `switch_to` swaps in the sender of a new channel under the same lock
+that every `send` takes, so each message goes either to the old channel or to
+the new one, never to both and never lost in between. The old sender is then
+dropped, which closes the old channel: its receiver still gets everything that
+was sent before the switch, and then sees the end of the stream. A consumer can
+thus drain the old channel and move over to the new one without a pause in
+the producers.
+
+Messages from one producer keep their order across the switch: all the ones
+that went to the old channel were sent before all the ones that went to the new
+channel. */
+
+pub struct Switch<T> {
+  target: Arc<Mutex<MultiSend<T>>>,
+}
+
+impl<T> Switch<T> {
+  pub fn new(sender: MultiSend<T>) -> Switch<T> {
+    Switch { target: Arc::new(Mutex::new(sender)) }
+  }
+
+  // Fails like `SharedSend::send`.
+
+  pub fn send(&self, msg: T) -> Result<(), TrySendError<T>> {
+    self.target.lock().unwrap().send_in_place(msg)
+  }
+
+  // Redirects all clones of this switch to `sender` and closes the channel they
+  // sent to before.
+
+  pub fn switch_to(&self, sender: MultiSend<T>) {
+    let old = std::mem::replace(&mut *self.target.lock().unwrap(), sender);
+    old.close();
+  }
+}
+
+impl<T> Clone for Switch<T> {
+  fn clone(&self) -> Self {
+    Switch { target: self.target.clone() }
+  }
+}
+
+#[test]
+fn test_switch_mid_stream() {
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::multishot::new_multi_chan;
+
+  let (old, old_r) = new_multi_chan();
+  let switch = Switch::new(old);
+  let producers: Vec<_> = (0..3).map(|p| {
+    let switch = switch.clone();
+    thread::spawn(move || {
+      for i in 0..200 {
+        switch.send((p, i)).unwrap();
+        if i % 20 == 0 {
+          thread::sleep(Duration::from_millis(1));
+        }
+      }
+    })
+  }).collect();
+
+  thread::sleep(Duration::from_millis(3));
+  let (new, new_r) = new_multi_chan();
+  switch.switch_to(new);
+  drop(switch);
+
+  // The old channel ends at the switch, so draining it terminates while the
+  // producers are still sending.
+  let before: Vec<_> = old_r.into_iter().collect();
+  for h in producers {
+    h.join().unwrap();
+  }
+  let after: Vec<_> = new_r.into_iter().collect();
+
+  for p in 0..3 {
+    let mut seen: Vec<_> = before.iter().filter(|m| m.0 == p).map(|m| m.1).collect();
+    seen.extend(after.iter().filter(|m| m.0 == p).map(|m| m.1));
+    assert_eq!(seen, (0..200).collect::<Vec<_>>());
+  }
+}
@@ -0,0 +1,429 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/* Synchronization primitives built from `Mutex` and `Condvar`. */
+
+
+/* A `OnceFlag` runs an initializer exactly once, like `std::sync::Once`, with
+two differences:
+
+ - If the initializer panics (or `try_call_once` returns an error), the flag
+   is not poisoned. It goes back to the incomplete state and the next caller
+   runs its initializer instead.
+ - Other threads can `wait()` for the initialization to complete, optionally
+   with a timeout, without having to supply an initializer themselves. */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OnceState {
+  Incomplete,
+  Running,
+  Complete,
+}
+
+pub struct OnceFlag {
+  state: Mutex<OnceState>,
+  cond: Condvar,
+}
+
+// Resets the flag if the initializer does not finish, i.e. when it panics.
+
+struct ResetOnUnwind<'a> {
+  flag: &'a OnceFlag,
+  armed: bool,
+}
+
+impl Drop for ResetOnUnwind<'_> {
+  fn drop(&mut self) {
+    if self.armed {
+      self.flag.set(OnceState::Incomplete);
+    }
+  }
+}
+
+impl Default for OnceFlag {
+  fn default() -> Self {
+    OnceFlag::new()
+  }
+}
+
+impl OnceFlag {
+  pub const fn new() -> OnceFlag {
+    OnceFlag { state: Mutex::new(OnceState::Incomplete), cond: Condvar::new() }
+  }
+
+  pub fn is_completed(&self) -> bool {
+    *self.lock() == OnceState::Complete
+  }
+
+  // Runs `f` if no initializer has completed yet. If another thread is running
+  // its initializer, this waits for it. If that one fails, this thread takes
+  // over and runs `f`.
+
+  pub fn call_once(&self, f: impl FnOnce()) {
+    let _ = self.try_call_once(|| -> Result<(), ()> {
+      f();
+      Ok(())
+    });
+  }
+
+  // Like `call_once`, but the initializer can fail. On `Err` the flag stays
+  // incomplete, the error is returned, and a later call may try again.
+
+  pub fn try_call_once<E>(&self, f: impl FnOnce() -> Result<(), E>) -> Result<(), E> {
+    let mut state = self.lock();
+    loop {
+      match *state {
+        OnceState::Complete => return Ok(()),
+        OnceState::Running => state = self.cond.wait(state).unwrap(),
+        OnceState::Incomplete => break,
+      }
+    }
+    *state = OnceState::Running;
+    drop(state);
+
+    let mut guard = ResetOnUnwind { flag: self, armed: true };
+    let result = f();
+    guard.armed = false;
+    match result {
+      Ok(()) => self.set(OnceState::Complete),
+      Err(_) => self.set(OnceState::Incomplete),
+    }
+    result
+  }
+
+  // Blocks until an initializer has completed.
+
+  pub fn wait(&self) {
+    let state = self.lock();
+    let _state = self.cond.wait_while(state, |s| *s != OnceState::Complete).unwrap();
+  }
+
+  // Blocks until an initializer has completed or `timeout` has elapsed.
+  // Returns whether the flag is complete.
+
+  pub fn wait_timeout(&self, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.lock();
+    while *state != OnceState::Complete {
+      let now = Instant::now();
+      if now >= deadline {
+        return false;
+      }
+      state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+    }
+    true
+  }
+
+  fn set(&self, new: OnceState) {
+    *self.lock() = new;
+    self.cond.notify_all();
+  }
+
+  // The state is only ever assigned whole values, so a panic while holding the
+  // lock cannot leave it inconsistent; ignore poisoning.
+
+  fn lock(&self) -> MutexGuard<'_, OnceState> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+#[test]
+fn test_once_flag_runs_once() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  let flag = Arc::new(OnceFlag::new());
+  let count = Arc::new(AtomicUsize::new(0));
+  let handles: Vec<_> = (0..8).map(|_| {
+    let flag = flag.clone();
+    let count = count.clone();
+    thread::spawn(move || {
+      flag.call_once(|| {
+        thread::sleep(Duration::from_millis(10));
+        count.fetch_add(1, Ordering::SeqCst);
+      });
+      assert!(flag.is_completed());
+    })
+  }).collect();
+  for h in handles {
+    h.join().unwrap();
+  }
+  assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_once_flag_retry_after_panic() {
+  use std::panic;
+
+  let flag = OnceFlag::new();
+  let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+    flag.call_once(|| panic!("init failed"));
+  }));
+  assert!(r.is_err());
+  assert!(!flag.is_completed());
+
+  assert_eq!(flag.try_call_once(|| Err("still failing")), Err("still failing"));
+  assert!(!flag.is_completed());
+
+  let mut ran = false;
+  flag.call_once(|| ran = true);
+  assert!(ran);
+  assert!(flag.is_completed());
+}
+
+#[test]
+fn test_once_flag_wait() {
+  use std::thread;
+
+  let flag = Arc::new(OnceFlag::new());
+  assert!(!flag.wait_timeout(Duration::from_millis(10)));
+
+  let f = flag.clone();
+  let h = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(50));
+    f.call_once(|| ());
+  });
+  flag.wait();
+  assert!(flag.wait_timeout(Duration::from_millis(0)));
+  h.join().unwrap();
+}
+
+
+/* A `Gate` lets an operator pause a set of worker threads at a safe point and
+resume them later. Workers call `pass()` at the points where they may be
+paused; it returns immediately while the gate is open and blocks while it is
+closed.
+
+Every `open()` starts a new generation. A worker blocked in `pass()` is
+released once the generation it started waiting in has ended, even if the gate
+was closed again before it got to run. */
+
+struct GateState {
+  open: bool,
+  generation: u64,
+}
+
+pub struct Gate {
+  state: Mutex<GateState>,
+  cond: Condvar,
+}
+
+impl Default for Gate {
+  fn default() -> Self {
+    Gate::new()
+  }
+}
+
+impl Gate {
+  // Creates an open gate.
+
+  pub const fn new() -> Gate {
+    Gate { state: Mutex::new(GateState { open: true, generation: 0 }), cond: Condvar::new() }
+  }
+
+  pub fn is_open(&self) -> bool {
+    self.state.lock().unwrap().open
+  }
+
+  // Workers calling `pass()` from now on block until the next `open()`.
+
+  pub fn close(&self) {
+    self.state.lock().unwrap().open = false;
+  }
+
+  // Releases all blocked workers.
+
+  pub fn open(&self) {
+    let mut state = self.state.lock().unwrap();
+    if !state.open {
+      state.open = true;
+      state.generation += 1;
+      self.cond.notify_all();
+    }
+  }
+
+  // Blocks while the gate is closed.
+
+  pub fn pass(&self) {
+    let state = self.state.lock().unwrap();
+    let generation = state.generation;
+    let _state = self.cond.wait_while(state, |s| !s.open && s.generation == generation).unwrap();
+  }
+}
+
+#[test]
+fn test_gate_pause_resume() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  let gate = Arc::new(Gate::new());
+  let passed = Arc::new(AtomicUsize::new(0));
+  gate.close();
+  assert!(!gate.is_open());
+
+  let handles: Vec<_> = (0..4).map(|_| {
+    let gate = gate.clone();
+    let passed = passed.clone();
+    thread::spawn(move || {
+      gate.pass();
+      passed.fetch_add(1, Ordering::SeqCst);
+    })
+  }).collect();
+
+  thread::sleep(Duration::from_millis(50));
+  assert_eq!(passed.load(Ordering::SeqCst), 0);
+
+  // Closing right after opening must not strand the waiting workers.
+  gate.open();
+  gate.close();
+  for h in handles {
+    h.join().unwrap();
+  }
+  assert_eq!(passed.load(Ordering::SeqCst), 4);
+
+  gate.open();
+  gate.pass();
+}
+
+
+/* A `CancelToken` is a shared flag for cooperative cancellation. Every clone
+refers to the same flag. Long-running work checks `is_cancelled()` at
+convenient points, or sleeps with `wait_timeout` so that it wakes up as soon as
+the token is cancelled. Cancellation cannot be undone. */
+
+#[derive(Clone, Default)]
+pub struct CancelToken {
+  inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+  pub fn new() -> CancelToken {
+    CancelToken::default()
+  }
+
+  pub fn cancel(&self) {
+    let (cancelled, cond) = &*self.inner;
+    *cancelled.lock().unwrap() = true;
+    cond.notify_all();
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    *self.inner.0.lock().unwrap()
+  }
+
+  // Waits until the token is cancelled or `timeout` has elapsed. Returns
+  // whether the token is cancelled.
+
+  pub fn wait_timeout(&self, timeout: Duration) -> bool {
+    let (cancelled, cond) = &*self.inner;
+    let guard = cancelled.lock().unwrap();
+    let (guard, _) = cond.wait_timeout_while(guard, timeout, |c| !*c).unwrap();
+    *guard
+  }
+}
+
+#[test]
+fn test_cancel_token() {
+  use std::thread;
+
+  let token = CancelToken::new();
+  assert!(!token.is_cancelled());
+  assert!(!token.wait_timeout(Duration::from_millis(10)));
+
+  let t = token.clone();
+  let h = thread::spawn(move || t.wait_timeout(Duration::from_secs(5)));
+  thread::sleep(Duration::from_millis(20));
+  token.cancel();
+  assert!(h.join().unwrap());
+  assert!(token.is_cancelled());
+}
+
+
+/* A `Versioned<T>` supports optimistic concurrency. A worker reads the value
+together with its version, computes an update without holding the lock, and
+then applies it with `compare_and_update`, which only succeeds if nobody else
+has updated the value in the meantime. On failure the worker reads again and
+retries. */
+
+// The version of a `Versioned` value. It increases with every update.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(u64);
+
+#[derive(Default)]
+pub struct Versioned<T> {
+  state: Mutex<(T, Version)>,
+}
+
+impl<T> Versioned<T> {
+  pub fn new(value: T) -> Versioned<T> {
+    Versioned { state: Mutex::new((value, Version(0))) }
+  }
+
+  pub fn version(&self) -> Version {
+    self.state.lock().unwrap().1
+  }
+
+  // Applies `f` to the value if it is still at `version`, and returns the new
+  // version. Otherwise leaves the value alone and returns the current version
+  // as the error.
+
+  pub fn compare_and_update(&self, version: Version, f: impl FnOnce(&mut T)) -> Result<Version, Version> {
+    let mut state = self.state.lock().unwrap();
+    if state.1 != version {
+      return Err(state.1);
+    }
+    f(&mut state.0);
+    state.1 = Version(version.0 + 1);
+    Ok(state.1)
+  }
+}
+
+impl<T: Clone> Versioned<T> {
+  // A copy of the value and its version.
+
+  pub fn read(&self) -> (T, Version) {
+    let state = self.state.lock().unwrap();
+    (state.0.clone(), state.1)
+  }
+}
+
+#[test]
+fn test_versioned_conflict() {
+  let v = Versioned::new(vec![1]);
+  let (mut a, version) = v.read();
+  let (mut b, _) = v.read();
+
+  a.push(2);
+  let new_version = v.compare_and_update(version, |x| *x = a).unwrap();
+  assert!(new_version > version);
+
+  // `b` was computed from the old version, so it is rejected.
+  b.push(3);
+  assert_eq!(v.compare_and_update(version, |x| *x = b), Err(new_version));
+  assert_eq!(v.read(), (vec![1, 2], new_version));
+}
+
+#[test]
+fn test_versioned_retry_loop() {
+  use std::thread;
+
+  let v = Arc::new(Versioned::new(0));
+  let handles: Vec<_> = (0..4).map(|_| {
+    let v = v.clone();
+    thread::spawn(move || {
+      for _ in 0..100 {
+        loop {
+          let (n, version) = v.read();
+          if v.compare_and_update(version, |x| *x = n + 1).is_ok() {
+            break;
+          }
+        }
+      }
+    })
+  }).collect();
+  for h in handles {
+    h.join().unwrap();
+  }
+  assert_eq!(v.read().0, 400);
+}
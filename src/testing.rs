@@ -0,0 +1,276 @@
+use std::fmt::Debug;
+
+use crate::errors::TryRecvError;
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::pipeline::Pipeline;
+
+/* Scripted channels for unit tests.
+
+Components built on this crate take channel ends as arguments. To test such a
+component without producer and consumer threads, hand it the ends of scripted
+channels instead:
+
+ - A `MockReceiver` feeds canned messages into the receiver given to the
+   component. Everything is sent up front, so the component can run on the
+   test thread and never blocks on its input.
+ - A `MockSender` records what the component sends on the sender given to it.
+   `verify()` then checks the messages against the expected sequence, and
+   checks whether the component closed the sender.
+
+Both are real multi-shot channels, so the component cannot tell the
+difference. */
+
+pub struct MockReceiver<T> {
+  sender: Option<MultiSend<T>>,
+}
+
+impl<T> MockReceiver<T> {
+  // A mock and the receiver to give to the component under test.
+
+  pub fn new() -> (MockReceiver<T>, MultiRecv<T>) {
+    let (s, r) = new_multi_chan();
+    (MockReceiver { sender: Some(s) }, r)
+  }
+
+  // A receiver that yields `msgs` and is then closed.
+
+  pub fn scripted(msgs: impl IntoIterator<Item = T>) -> MultiRecv<T> {
+    let (mut mock, r) = MockReceiver::new();
+    for msg in msgs {
+      mock.feed(msg);
+    }
+    r
+  }
+
+  // Makes `msg` the next message the component receives. Messages fed after
+  // the component dropped its receiver are discarded.
+
+  pub fn feed(&mut self, msg: T) -> &mut MockReceiver<T> {
+    if let Some(s) = &mut self.sender {
+      if s.try_send(msg).is_err() {
+        self.sender = None;
+      }
+    }
+    self
+  }
+
+  // Closes the channel after the messages fed so far. Dropping the mock has the
+  // same effect.
+
+  pub fn close(self) {}
+}
+
+pub struct MockSender<T> {
+  receiver: MultiRecv<T>,
+  expected: Vec<T>,
+  closed: bool,
+}
+
+impl<T: PartialEq + Debug> MockSender<T> {
+  // A mock and the sender to give to the component under test.
+
+  pub fn new() -> (MultiSend<T>, MockSender<T>) {
+    let (s, r) = new_multi_chan();
+    (s, MockSender { receiver: r, expected: Vec::new(), closed: false })
+  }
+
+  // Expects `msg` as the next message.
+
+  pub fn expect_send(mut self, msg: T) -> MockSender<T> {
+    self.expected.push(msg);
+    self
+  }
+
+  // Expects the component to close the sender (or drop it) after the expected
+  // messages. Without this, `verify` checks that the sender is still open.
+
+  pub fn expect_close(mut self) -> MockSender<T> {
+    self.closed = true;
+    self
+  }
+
+  // Panics if the messages sent so far differ from the expected ones, or if
+  // the sender was (not) closed against expectations. Does not block.
+
+  pub fn verify(mut self) {
+    let sent: Vec<T> = self.receiver.try_iter().collect();
+    assert_eq!(sent, self.expected, "MockSender: unexpected messages");
+    match self.receiver.try_recv() {
+      Err(TryRecvError::Disconnected) => assert!(self.closed, "MockSender: sender closed unexpectedly"),
+      Err(TryRecvError::Empty) => assert!(!self.closed, "MockSender: sender was not closed"),
+      Ok(_) => unreachable!("try_iter stops at the first missing message"),
+    }
+  }
+}
+
+/* `expect_flow` is a one-call black-box test for a dataflow that turns one
+stream into another, such as a chain of combinators. It feeds the inputs to the
+flow, closes its input, collects everything the flow sends until it closes its
+output, and compares that with the expected outputs. On a mismatch, it reports
+the first position where they differ. `expect_pipeline` does the same for the
+stages of a `Pipeline`, and also joins them, so that a stage that panicked
+fails the test.
+
+Both run the flow on real threads, and compare the outputs by position. The
+result is therefore only reproducible for flows whose output order does not
+depend on timing. A linear pipeline qualifies, as every stage passes on the
+messages in the order it received them; stages that interleave several inputs,
+such as `merge`, need a different check.
+
+`expect_pipeline_stepped` is the deterministic mode. It builds the stages on a
+`Pipeline::stepped`, which runs them all on the test thread, one message at a
+time, so every run executes the stages in the same order. A stage that panics
+fails the test right away. */
+
+pub fn expect_flow<I, O, F>(flow: F, inputs: impl IntoIterator<Item = I>, expected: impl IntoIterator<Item = O>)
+where
+  O: PartialEq + Debug,
+  F: FnOnce(MultiRecv<I>) -> MultiRecv<O>,
+{
+  let outputs: Vec<O> = flow(MockReceiver::scripted(inputs)).into_iter().collect();
+  compare_flow(outputs, expected.into_iter().collect());
+}
+
+// Runs the stages that `build` adds to a pipeline with channels of `capacity`
+// messages, fed with `inputs`, and checks its output like `expect_flow`.
+
+pub fn expect_pipeline<I, O, F>(capacity: usize, build: F, inputs: impl IntoIterator<Item = I>, expected: impl IntoIterator<Item = O>)
+where
+  I: Send + 'static,
+  O: PartialEq + Debug + Send + 'static,
+  F: FnOnce(Pipeline<I>) -> Pipeline<O>,
+{
+  let (output, handle) = build(Pipeline::from_recv(capacity, MockReceiver::scripted(inputs))).into_recv();
+  let outputs: Vec<O> = output.into_iter().collect();
+  if let Err(panic) = handle.join() {
+    panic!("expect_pipeline: a stage panicked: {:?}", panic);
+  }
+  compare_flow(outputs, expected.into_iter().collect());
+}
+
+// Runs the stages that `build` adds to a stepped pipeline, fed with `inputs`,
+// and checks its output like `expect_flow`.
+
+pub fn expect_pipeline_stepped<I, O, F>(build: F, inputs: impl IntoIterator<Item = I>, expected: impl IntoIterator<Item = O>)
+where
+  I: Send + 'static,
+  O: PartialEq + Debug + Send + 'static,
+  F: FnOnce(Pipeline<I>) -> Pipeline<O>,
+{
+  let inputs: Vec<I> = inputs.into_iter().collect();
+  let (output, _) = build(Pipeline::stepped(inputs)).into_recv();
+  compare_flow(output.into_iter().collect(), expected.into_iter().collect());
+}
+
+fn compare_flow<O: PartialEq + Debug>(outputs: Vec<O>, expected: Vec<O>) {
+  if let Some(i) = outputs.iter().zip(&expected).position(|(o, e)| o != e) {
+    panic!("expect_flow: output {} is {:?}, expected {:?}", i, outputs[i], expected[i]);
+  }
+  assert_eq!(
+    outputs.len(),
+    expected.len(),
+    "expect_flow: got {} outputs, expected {}; outputs: {:?}, expected: {:?}",
+    outputs.len(),
+    expected.len(),
+    outputs,
+    expected,
+  );
+}
+
+#[test]
+fn test_mocks_drive_component() {
+  // A component that forwards the even numbers it receives.
+  fn evens(input: MultiRecv<i32>, mut output: MultiSend<i32>) {
+    for n in input {
+      if n % 2 == 0 {
+        output = output.send(n).unwrap();
+      }
+    }
+  }
+
+  let (output, mock) = MockSender::new();
+  evens(MockReceiver::scripted(1..=5), output);
+  mock.expect_send(2).expect_send(4).expect_close().verify();
+}
+
+#[test]
+fn test_mock_receiver_feed() {
+  let (mut mock, mut r) = MockReceiver::new();
+  mock.feed("a").feed("b");
+  assert_eq!(r.try_iter().collect::<Vec<_>>(), vec!["a", "b"]);
+  assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+  mock.close();
+  assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+#[should_panic(expected = "unexpected messages")]
+fn test_mock_sender_mismatch() {
+  let (s, mock) = MockSender::new();
+  let _s = s.send(1).unwrap();
+  mock.expect_send(2).verify();
+}
+
+#[test]
+fn test_expect_flow() {
+  use std::time::Duration;
+
+  use crate::batcher::ByKey;
+
+  // Batches of two items by parity, on the stage's own thread.
+  let flow = |input: MultiRecv<u32>| {
+    let (mut s, keyed) = new_multi_chan();
+    for n in input {
+      s = s.send((n % 2, n)).unwrap();
+    }
+    drop(s);
+    ByKey::new(2, Duration::from_secs(10)).spawn(keyed).0
+  };
+  expect_flow(flow, 1..=5, [(1, vec![1, 3]), (0, vec![2, 4]), (1, vec![5])]);
+}
+
+#[test]
+#[should_panic(expected = "output 1 is 4, expected 3")]
+fn test_expect_flow_mismatch() {
+  let double = |input: MultiRecv<u32>| {
+    let (mut s, r) = new_multi_chan();
+    for n in input {
+      s = s.send(n * 2).unwrap();
+    }
+    r
+  };
+  expect_flow(double, [1, 2], [2, 3]);
+}
+
+#[test]
+fn test_expect_pipeline() {
+  expect_pipeline(2, |p| p.map(|n: u32| n * 3).filter(|n| n % 2 == 0), 0..10, [0, 6, 12, 18, 24]);
+}
+
+#[test]
+#[should_panic(expected = "a stage panicked")]
+fn test_expect_pipeline_stage_panics() {
+  expect_pipeline(2, |p| p.map(|n: u32| if n == 2 { panic!("bad item") } else { n }), 0..4, [0, 1]);
+}
+
+#[test]
+fn test_expect_pipeline_stepped() {
+  use std::sync::{Arc, Mutex};
+
+  // A stage that keeps state across messages sees them in input order.
+  let seen = Arc::new(Mutex::new(Vec::new()));
+  let s = seen.clone();
+  let running_sum = move |n: u32| {
+    let mut seen = s.lock().unwrap();
+    seen.push(n);
+    seen.iter().sum::<u32>()
+  };
+  expect_pipeline_stepped(|p| p.map(running_sum).filter(|n| n % 2 == 0), 1..=5, [6, 10]);
+  assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+#[should_panic(expected = "bad item")]
+fn test_expect_pipeline_stepped_stage_panics() {
+  expect_pipeline_stepped(|p| p.map(|n: u32| if n == 2 { panic!("bad item") } else { n }), 0..4, [0, 1]);
+}
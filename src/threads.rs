@@ -0,0 +1,240 @@
+use std::panic;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle, Scope, ScopedJoinHandle, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::multishot::{new_bounded_multi_chan_with_policy, MultiRecv, MultiSend, OverflowPolicy};
+
+/* Helpers for working with join handles, and lifecycle events for the threads
+spawned by this crate. */
+
+// Waits up to `timeout` for the threads in `handles` and joins the ones that
+// finish in time. Returns their results, in the order of `handles`, together
+// with the handles of the threads that are still running, so the caller can
+// carry on with partial results and deal with the stragglers later.
+//
+// `JoinHandle` has no timed join, so this polls `is_finished` with a short,
+// growing interval. If a joined thread panicked, the panic is propagated to the
+// caller, as with `handle.join().unwrap()`.
+
+pub fn join_all_timeout<T>(handles: Vec<JoinHandle<T>>, timeout: Duration) -> (Vec<T>, Vec<JoinHandle<T>>) {
+  let deadline = Instant::now() + timeout;
+  let mut pause = Duration::from_millis(1);
+  loop {
+    let now = Instant::now();
+    if now >= deadline || handles.iter().all(|h| h.is_finished()) {
+      break;
+    }
+    thread::sleep(pause.min(deadline - now));
+    pause = (pause * 2).min(Duration::from_millis(10));
+  }
+
+  let mut done = Vec::new();
+  let mut running = Vec::new();
+  for h in handles {
+    if h.is_finished() {
+      match h.join() {
+        Ok(v) => done.push(v),
+        Err(e) => panic::resume_unwind(e),
+      }
+    } else {
+      running.push(h);
+    }
+  }
+  (done, running)
+}
+
+/* Thread lifecycle events.
+
+Every thread the crate spawns reports when it starts and when it finishes or
+panics: the ones of `background::spawn` and `Nursery::spawn`, and the ones the
+crate starts for its own work, such as the workers of `parallel` and the
+forwarding threads of `combine`. `events()` subscribes to these reports, e.g.
+for a dashboard or for tests that check that no thread is left behind. Each
+subscriber gets the events emitted after it subscribed.
+
+A subscriber that does not keep up only keeps the latest `EVENT_BACKLOG`
+events; older ones are dropped. So a receiver that is never read holds on to a
+bounded amount of memory. Dropping the receiver unsubscribes it. */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ThreadEvent {
+  Started { name: String, id: ThreadId },
+  Finished { name: String, id: ThreadId, duration: Duration },
+  Panicked { name: String, id: ThreadId, duration: Duration },
+}
+
+impl ThreadEvent {
+  pub fn name(&self) -> &str {
+    match self {
+      ThreadEvent::Started { name, .. } | ThreadEvent::Finished { name, .. } | ThreadEvent::Panicked { name, .. } => name,
+    }
+  }
+
+  pub fn id(&self) -> ThreadId {
+    match self {
+      ThreadEvent::Started { id, .. } | ThreadEvent::Finished { id, .. } | ThreadEvent::Panicked { id, .. } => *id,
+    }
+  }
+}
+
+// How many unread events a subscriber keeps.
+
+pub const EVENT_BACKLOG: usize = 1024;
+
+static SUBSCRIBERS: Mutex<Vec<MultiSend<ThreadEvent>>> = Mutex::new(Vec::new());
+
+// Subscribes to the lifecycle events of threads spawned from now on.
+
+pub fn events() -> MultiRecv<ThreadEvent> {
+  let (s, r) = new_bounded_multi_chan_with_policy(EVENT_BACKLOG, OverflowPolicy::DropOldest);
+  SUBSCRIBERS.lock().unwrap().push(s);
+  r
+}
+
+fn emit(event: ThreadEvent) {
+  let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner());
+  // Sending only fails once the receiver is gone, so drop those subscribers.
+  subscribers.retain_mut(|s| s.try_send(event.clone()).is_ok());
+}
+
+// Emits `Started` when created on a new thread, and `Finished` or `Panicked`
+// when dropped at the end of it.
+
+pub(crate) struct Lifecycle {
+  name: String,
+  start: Instant,
+}
+
+impl Lifecycle {
+  pub(crate) fn start(name: String) -> Lifecycle {
+    emit(ThreadEvent::Started { name: name.clone(), id: thread::current().id() });
+    Lifecycle { name, start: Instant::now() }
+  }
+}
+
+impl Drop for Lifecycle {
+  fn drop(&mut self) {
+    let name = std::mem::take(&mut self.name);
+    let id = thread::current().id();
+    let duration = self.start.elapsed();
+    if thread::panicking() {
+      emit(ThreadEvent::Panicked { name, id, duration });
+    } else {
+      emit(ThreadEvent::Finished { name, id, duration });
+    }
+  }
+}
+
+// Spawns a thread named `name` that reports its lifecycle events. The crate
+// spawns its own threads through here.
+
+pub(crate) fn spawn<T, F>(name: String, f: F) -> JoinHandle<T>
+where
+  T: Send + 'static,
+  F: FnOnce() -> T + Send + 'static,
+{
+  thread::Builder::new()
+    .name(name.clone())
+    .spawn(move || {
+      let _lifecycle = Lifecycle::start(name);
+      f()
+    })
+    .expect("failed to spawn thread")
+}
+
+// `spawn` for a thread in a `thread::scope`.
+
+pub(crate) fn spawn_scoped<'scope, T, F>(s: &'scope Scope<'scope, '_>, name: String, f: F) -> ScopedJoinHandle<'scope, T>
+where
+  T: Send + 'scope,
+  F: FnOnce() -> T + Send + 'scope,
+{
+  thread::Builder::new()
+    .name(name.clone())
+    .spawn_scoped(s, move || {
+      let _lifecycle = Lifecycle::start(name);
+      f()
+    })
+    .expect("failed to spawn thread")
+}
+
+#[test]
+fn test_join_all_timeout_partial() {
+  let handles: Vec<_> = [10, 20, 1000, 30].iter().map(|&ms| {
+    thread::spawn(move || {
+      thread::sleep(Duration::from_millis(ms));
+      ms
+    })
+  }).collect();
+
+  let (done, running) = join_all_timeout(handles, Duration::from_millis(300));
+  assert_eq!(done, vec![10, 20, 30]);
+  assert_eq!(running.len(), 1);
+
+  let (done, running) = join_all_timeout(running, Duration::from_secs(5));
+  assert_eq!(done, vec![1000]);
+  assert!(running.is_empty());
+}
+
+#[test]
+fn test_join_all_timeout_returns_early() {
+  let handles = vec![thread::spawn(|| 1), thread::spawn(|| 2)];
+  let start = Instant::now();
+  let (done, running) = join_all_timeout(handles, Duration::from_secs(10));
+  assert_eq!(done, vec![1, 2]);
+  assert!(running.is_empty());
+  assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_thread_events() {
+  use crate::nursery::Nursery;
+
+  let mut events = events();
+  let mut n = Nursery::new();
+  n.spawn(|_| thread::current().id());
+  n.spawn(|_| -> ThreadId { panic!("child failed") });
+  let results = n.close();
+  let ok = *results[0].as_ref().unwrap();
+
+  // Other tests may spawn threads at the same time, so look only at the events
+  // of the child that succeeded, and at panics of nursery children.
+  let events: Vec<_> = events.try_iter().collect();
+  let mine: Vec<_> = events.iter().filter(|e| e.id() == ok).collect();
+  assert_eq!(mine.len(), 2);
+  assert!(matches!(mine[0], ThreadEvent::Started { .. }));
+  assert!(matches!(mine[1], ThreadEvent::Finished { .. }));
+  assert!(mine.iter().all(|e| e.name().starts_with("nursery-")));
+  assert!(events.iter().any(|e| matches!(e, ThreadEvent::Panicked { name, .. } if name == "nursery-1")));
+}
+
+#[test]
+fn test_crate_threads_report_events() {
+  use crate::combine::merge;
+  use crate::multishot::new_multi_chan;
+  use crate::parallel::for_each_concurrent;
+
+  let mut events = events();
+  let (s, r) = new_multi_chan::<u32>();
+  drop(s);
+  assert!(merge(vec![r]).recv().is_none());
+  let _: Result<(), _> = for_each_concurrent(0..2, 1, |_| Ok::<(), ()>(()));
+
+  // The merged stream is closed before the merge thread has finished, but
+  // after it has started. The scoped workers are joined before returning.
+  let events: Vec<_> = events.try_iter().collect();
+  assert!(events.iter().any(|e| matches!(e, ThreadEvent::Started { name, .. } if name == "merge")));
+  assert!(events.iter().any(|e| matches!(e, ThreadEvent::Finished { name, .. } if name == "for_each_concurrent-0")));
+}
+
+#[test]
+fn test_unread_subscriber_is_bounded() {
+  let mut events = events();
+  for i in 0..2 * EVENT_BACKLOG {
+    emit(ThreadEvent::Started { name: format!("backlog-{}", i), id: thread::current().id() });
+  }
+  let mine: Vec<_> = events.try_iter().filter(|e| e.name().starts_with("backlog-")).map(|e| e.name().to_string()).collect();
+  assert!(mine.len() <= EVENT_BACKLOG);
+  assert_eq!(mine.last().map(|n| n.as_str()), Some(format!("backlog-{}", 2 * EVENT_BACKLOG - 1).as_str()));
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use crate::sync::CancelToken;
+
+/* Sleeping in worker loops.
+
+A worker that polls with `thread::sleep` only notices a shutdown request once
+its current sleep is over. `sleep_cancellable` sleeps on the token instead, so
+the worker wakes up as soon as the token is cancelled. */
+
+// Sleeps for `dur`, or until `token` is cancelled if that happens first.
+// Returns whether the sleep was cut short, i.e. whether the worker should stop.
+
+pub fn sleep_cancellable(dur: Duration, token: &CancelToken) -> bool {
+  token.wait_timeout(dur)
+}
+
+#[test]
+fn test_sleep_cancellable() {
+  use std::thread;
+  use std::time::Instant;
+
+  let token = CancelToken::new();
+  let start = Instant::now();
+  assert!(!sleep_cancellable(Duration::from_millis(20), &token));
+  assert!(start.elapsed() >= Duration::from_millis(20));
+
+  let t = token.clone();
+  let h = thread::spawn(move || {
+    let mut rounds = 0;
+    while !sleep_cancellable(Duration::from_millis(100), &t) {
+      rounds += 1;
+    }
+    rounds
+  });
+  thread::sleep(Duration::from_millis(30));
+  let start = Instant::now();
+  token.cancel();
+  assert_eq!(h.join().unwrap(), 0);
+  assert!(start.elapsed() < Duration::from_millis(100));
+}
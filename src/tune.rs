@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::multishot::{new_bounded_multi_chan_with_policy, Bound, MultiRecv, MultiSend, OverflowPolicy};
+use crate::threads;
+
+/* Adaptive capacity for bounded channels.
+
+A channel that is too small makes its sender wait for every burst, and one
+that is too large lets a backlog grow and hides that the receiver cannot keep
+up. `auto_tune` takes the guesswork out of picking the size: a controller
+thread looks at the channel once every `interval` and adjusts its capacity
+within `min..=max`:
+
+ - If a sender found the channel full during the interval, the capacity is
+   doubled.
+ - If nobody did, and there were never more than a quarter of the capacity in
+   flight, it is halved.
+
+Every change is reported as a `Resize` on the returned receiver. As with
+`threads::events`, an unread receiver only keeps the latest `RESIZE_BACKLOG`
+of them. The controller stops once the channel is gone, that is, once its
+sender is dropped and all messages have been received or dropped. */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resize {
+  pub from: usize,
+  pub to: usize,
+}
+
+// How many unread resizes the receiver of `auto_tune` keeps.
+
+pub const RESIZE_BACKLOG: usize = 64;
+
+// Starts a controller for the capacity of the channel of `sender`, which must
+// be bounded. A capacity outside `min..=max` is moved into it right away.
+
+pub fn auto_tune<T>(sender: &MultiSend<T>, min: usize, max: usize, interval: Duration) -> MultiRecv<Resize> {
+  assert!(0 < min && min <= max, "auto_tune: need 0 < min <= max");
+  let bound = sender.bound().expect("auto_tune: the channel is not bounded");
+  let (mut resizes, r) = new_bounded_multi_chan_with_policy(RESIZE_BACKLOG, OverflowPolicy::DropOldest);
+  let from = bound.capacity();
+  let to = from.clamp(min, max);
+  if to != from {
+    bound.resize(to);
+    let _ = resizes.try_send(Resize { from, to });
+  }
+  let bound = Arc::downgrade(bound);
+  threads::spawn("auto-tune".to_string(), move || loop {
+      thread::sleep(interval);
+      let Some(bound) = bound.upgrade() else { return };
+      if let Some(resize) = adjust(&bound, min, max) {
+        // Keep tuning even if nobody reads the resizes.
+        let _ = resizes.try_send(resize);
+      }
+  });
+  r
+}
+
+fn adjust(bound: &Bound, min: usize, max: usize) -> Option<Resize> {
+  let (full, peak) = bound.take_stats();
+  let from = bound.capacity();
+  let to = if full > 0 {
+    (from * 2).min(max)
+  } else if peak <= from / 4 {
+    (from / 2).max(min)
+  } else {
+    from
+  };
+  if to == from {
+    return None;
+  }
+  bound.resize(to);
+  Some(Resize { from, to })
+}
+
+#[test]
+fn test_auto_tune_grows_and_shrinks() {
+  use crate::multishot::new_bounded_multi_chan;
+
+  let (mut s, r) = new_bounded_multi_chan(1);
+  let resizes = auto_tune(&s, 1, 8, Duration::from_millis(20));
+
+  // A producer that is faster than the consumer keeps the channel full.
+  let consumer = thread::spawn(move || {
+    let mut r = r;
+    for _ in 0..300 {
+      r = r.recv().unwrap().1;
+      thread::sleep(Duration::from_millis(1));
+    }
+    r
+  });
+  for i in 0..300 {
+    s = s.send(i).unwrap();
+  }
+  let r = consumer.join().unwrap();
+  // Then the channel is idle for a while.
+  thread::sleep(Duration::from_millis(200));
+
+  // The controller stops once the channel is gone, which ends the resizes.
+  drop((s, r));
+  let sizes: Vec<_> = resizes.into_iter().map(|r| (r.from, r.to)).collect();
+  assert_eq!(sizes, [(1, 2), (2, 4), (4, 8), (8, 4), (4, 2), (2, 1)]);
+}
+
+#[test]
+fn test_auto_tune_clamps_initial_capacity() {
+  use crate::multishot::new_bounded_multi_chan;
+
+  let (s, _r) = new_bounded_multi_chan::<()>(100);
+  let mut resizes = auto_tune(&s, 1, 10, Duration::from_secs(60));
+  assert_eq!(resizes.try_recv(), Ok(Resize { from: 100, to: 10 }));
+  assert_eq!(s.bound().unwrap().capacity(), 10);
+}
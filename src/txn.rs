@@ -0,0 +1,63 @@
+use crate::errors::TrySendError;
+use crate::multishot::MultiSend;
+
+/* Sending to several channels at once.
+
+A fan-out that sends one message after the other can stop halfway, when one
+of the channels is full, and leave the messages it sent before in flight.
+`send_all_or_none` avoids that in two phases: it first reserves room on every
+channel, and only once it has all the permits does it send the messages with
+them. If a channel is full, the permits taken so far are dropped, which gives
+their room back, and nothing is sent.
+
+A channel whose receiver is gone fails the whole send as well. A receiver can
+still go away between the check and the send, but then its message would have
+been lost anyway. */
+
+// Sends each message on its sender, or none of them if one of the channels is
+// full or its receiver is gone. Never blocks. On failure, all messages are
+// handed back, in order.
+
+pub fn send_all_or_none<T>(sends: Vec<(&mut MultiSend<T>, T)>) -> Result<(), TrySendError<Vec<T>>> {
+  let (senders, msgs): (Vec<_>, Vec<_>) = sends.into_iter().unzip();
+  if senders.iter().any(|s| s.is_closed()) {
+    return Err(TrySendError::Disconnected(msgs));
+  }
+  let mut permits = Vec::with_capacity(senders.len());
+  for s in senders {
+    match s.try_reserve() {
+      Some(permit) => permits.push(permit),
+      None => return Err(TrySendError::Full(msgs)),
+    }
+  }
+  for (permit, msg) in permits.into_iter().zip(msgs) {
+    let _ = permit.send(msg);
+  }
+  Ok(())
+}
+
+#[test]
+fn test_send_all_or_none() {
+  use crate::errors::TryRecvError;
+  use crate::multishot::{new_bounded_multi_chan, new_multi_chan};
+
+  let (mut s1, mut r1) = new_bounded_multi_chan(1);
+  let (mut s2, mut r2) = new_bounded_multi_chan(1);
+  send_all_or_none(vec![(&mut s1, "a"), (&mut s2, "b")]).unwrap();
+  assert_eq!(r1.try_recv(), Ok("a"));
+
+  // The second channel is still full, so the first one gets nothing.
+  assert_eq!(send_all_or_none(vec![(&mut s1, "c"), (&mut s2, "d")]), Err(TrySendError::Full(vec!["c", "d"])));
+  assert_eq!(r1.try_recv(), Err(TryRecvError::Empty));
+  // Its capacity was given back.
+  assert!(s1.try_reserve().is_some());
+
+  assert_eq!(r2.try_recv(), Ok("b"));
+  send_all_or_none(vec![(&mut s1, "e"), (&mut s2, "f")]).unwrap();
+  assert_eq!((r1.try_recv(), r2.try_recv()), (Ok("e"), Ok("f")));
+
+  let (mut s3, r3) = new_multi_chan();
+  drop(r3);
+  assert_eq!(send_all_or_none(vec![(&mut s1, "g"), (&mut s3, "h")]), Err(TrySendError::Disconnected(vec!["g", "h"])));
+  assert_eq!(r1.try_recv(), Err(TryRecvError::Empty));
+}
@@ -0,0 +1,194 @@
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::errors::{RecvError, RecvTimeoutError, SendError};
+
+/* Watch channels.
+
+A watch channel holds a single value instead of a queue of messages. Sending
+replaces the value, and receivers look at the latest one with `borrow()`, or
+block in `wait_for_change()` until a new value is sent. Values that were
+replaced before a receiver looked at them are never seen by it. This suits
+slowly changing state, such as the current configuration, where only the
+latest value matters.
+
+Every send increments a version number, and each receiver remembers the
+version it saw last, so it can tell whether the value has changed since. */
+
+struct Repr<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar,
+}
+
+struct State<T> {
+  value: T,
+  version: u64,
+  sender_alive: bool,
+  receivers: usize,
+}
+
+pub struct Sender<T> {
+  repr: Arc<Repr<T>>,
+}
+
+// A receiver can be cloned; the clone has seen the same version as the
+// original.
+
+pub struct Receiver<T> {
+  repr: Arc<Repr<T>>,
+  seen: u64,
+}
+
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+  let state = State { value: initial, version: 0, sender_alive: true, receivers: 1 };
+  let repr = Arc::new(Repr { state: Mutex::new(state), cond: Condvar::new() });
+  (Sender { repr: repr.clone() }, Receiver { repr, seen: 0 })
+}
+
+impl<T> Sender<T> {
+  // Replaces the value and wakes up the waiting receivers. Fails, handing
+  // `value` back, if all receivers are gone.
+
+  pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+    let old = {
+      let mut state = self.repr.state.lock().unwrap();
+      if state.receivers == 0 {
+        return Err(SendError(value));
+      }
+      state.version += 1;
+      std::mem::replace(&mut state.value, value)
+    };
+    self.repr.cond.notify_all();
+    // The old value is dropped outside the lock.
+    drop(old);
+    Ok(())
+  }
+
+  // The current value, as receivers see it.
+
+  pub fn borrow(&self) -> Ref<'_, T> {
+    Ref { guard: self.repr.state.lock().unwrap() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    self.repr.state.lock().unwrap_or_else(|e| e.into_inner()).sender_alive = false;
+    self.repr.cond.notify_all();
+  }
+}
+
+impl<T> Receiver<T> {
+  // The current value. The value cannot change while the `Ref` is alive, so do
+  // not hold on to it for long. Borrowing does not mark the value as seen.
+
+  pub fn borrow(&self) -> Ref<'_, T> {
+    Ref { guard: self.repr.state.lock().unwrap() }
+  }
+
+  // Whether a value was sent that this receiver has not waited for yet.
+
+  pub fn has_changed(&self) -> bool {
+    self.repr.state.lock().unwrap().version != self.seen
+  }
+
+  // Blocks until a value is sent that this receiver has not seen yet, and
+  // marks it as seen. Fails once the sender is gone and there is no such value.
+
+  pub fn wait_for_change(&mut self) -> Result<(), RecvError> {
+    let seen = self.seen;
+    let state = self.repr.state.lock().unwrap();
+    let state = self.repr.cond.wait_while(state, |s| s.version == seen && s.sender_alive).unwrap();
+    if state.version == seen {
+      return Err(RecvError::Disconnected);
+    }
+    self.seen = state.version;
+    Ok(())
+  }
+
+  // Like `wait_for_change`, but gives up after `timeout`.
+
+  pub fn wait_for_change_timeout(&mut self, timeout: Duration) -> Result<(), RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.repr.state.lock().unwrap();
+    loop {
+      if state.version != self.seen {
+        self.seen = state.version;
+        return Ok(());
+      }
+      if !state.sender_alive {
+        return Err(RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(RecvTimeoutError::Timeout);
+      }
+      state = self.repr.cond.wait_timeout(state, deadline - now).unwrap().0;
+    }
+  }
+}
+
+impl<T> Clone for Receiver<T> {
+  fn clone(&self) -> Self {
+    self.repr.state.lock().unwrap().receivers += 1;
+    Receiver { repr: self.repr.clone(), seen: self.seen }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    self.repr.state.lock().unwrap_or_else(|e| e.into_inner()).receivers -= 1;
+  }
+}
+
+// A borrowed value of a watch channel. It holds the channel's lock.
+
+pub struct Ref<'a, T> {
+  guard: MutexGuard<'a, State<T>>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.guard.value
+  }
+}
+
+#[test]
+fn test_watch_latest_value() {
+  let (s, r) = channel("initial");
+  assert_eq!(*r.borrow(), "initial");
+  assert!(!r.has_changed());
+  s.send("first").unwrap();
+  s.send("second").unwrap();
+  assert!(r.has_changed());
+  // Only the latest value is kept.
+  assert_eq!(*r.borrow(), "second");
+  assert_eq!(*s.borrow(), "second");
+
+  drop(r);
+  assert_eq!(s.send("third"), Err(SendError("third")));
+}
+
+#[test]
+fn test_watch_wait_for_change() {
+  use std::thread;
+
+  let (s, mut r) = channel(0);
+  let mut r2 = r.clone();
+  let h = thread::spawn(move || {
+    r2.wait_for_change().unwrap();
+    *r2.borrow()
+  });
+  assert_eq!(r.wait_for_change_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+  s.send(5).unwrap();
+  assert_eq!(h.join().unwrap(), 5);
+  assert_eq!(r.wait_for_change(), Ok(()));
+  assert!(!r.has_changed());
+
+  drop(s);
+  assert_eq!(r.wait_for_change(), Err(RecvError::Disconnected));
+  assert_eq!(*r.borrow(), 5);
+}
@@ -0,0 +1,114 @@
+use crate::errors::TryRecvError;
+use crate::multishot::{new_multi_chan, MultiRecv, MultiSend};
+use crate::select::Select;
+use crate::threads;
+
+/* Weighted fair queuing.
+
+A `Merger` interleaves the messages of several receivers into one stream like
+`combine::merge`, but shares the output between them by weight instead of
+equally: with weights 4 and 1, the first input gets four messages through for
+every one of the second while both have messages waiting, e.g. to give one
+tenant of a pipeline 80% of its throughput and another 20%. An input with
+nothing waiting does not hold up the others, and its share goes to them.
+
+It works like `merge`, waiting on all inputs with a `Select` that prefers the
+input at the front of the list. An input may forward as many messages in a row
+as its weight; then it moves to the back of the list and gets its full weight
+again. */
+
+struct Input<T> {
+  receiver: MultiRecv<T>,
+  weight: u32,
+  // The messages it may still forward before moving to the back.
+  credit: u32,
+}
+
+pub struct Merger<T> {
+  inputs: Vec<Input<T>>,
+}
+
+impl<T> Default for Merger<T> {
+  fn default() -> Self {
+    Merger::new()
+  }
+}
+
+impl<T> Merger<T> {
+  pub fn new() -> Merger<T> {
+    Merger { inputs: Vec::new() }
+  }
+
+  pub fn add(&mut self, input: MultiRecv<T>, weight: u32) {
+    assert!(weight > 0, "wfq::Merger: weight must be positive");
+    self.inputs.push(Input { receiver: input, weight, credit: weight });
+  }
+}
+
+impl<T: Send + 'static> Merger<T> {
+  // Starts forwarding and returns the merged stream, which is closed once all
+  // inputs are.
+
+  pub fn merge(self) -> MultiRecv<T> {
+    let (output, merged) = new_multi_chan();
+    threads::spawn("wfq".to_string(), move || forward_weighted(self.inputs, output));
+    merged
+  }
+}
+
+fn forward_weighted<T>(mut inputs: Vec<Input<T>>, mut output: MultiSend<T>) {
+  while !inputs.is_empty() {
+    let i = {
+      let mut select = Select::new();
+      for input in &inputs {
+        select.add(&input.receiver);
+      }
+      select.ready()
+    };
+    match inputs[i].receiver.try_recv() {
+      Ok(msg) => {
+        output = match output.send(msg) {
+          Ok(output) => output,
+          Err(_) => return,
+        };
+        let input = &mut inputs[i];
+        input.credit -= 1;
+        if input.credit == 0 {
+          input.credit = input.weight;
+          inputs[i..].rotate_left(1);
+        }
+      }
+      // Not reached: `Select` only reports an input once it has a message or is
+      // closed.
+      Err(TryRecvError::Empty) => {}
+      Err(TryRecvError::Disconnected) => {
+        inputs.remove(i);
+      }
+    }
+  }
+}
+
+#[test]
+fn test_wfq_weighted_share() {
+  // Both inputs have all their messages ready from the start.
+  let input = |tenant: &'static str| {
+    let (mut s, r) = new_multi_chan();
+    for _ in 0..100 {
+      s = s.send(tenant).unwrap();
+    }
+    r
+  };
+  let mut merger = Merger::new();
+  merger.add(input("a"), 4);
+  merger.add(input("b"), 1);
+  let received: Vec<_> = merger.merge().into_iter().collect();
+
+  // While both have messages, "a" gets 80% of the output.
+  assert_eq!(received[..5], ["a", "a", "a", "a", "b"]);
+  for n in [50, 100] {
+    assert_eq!(received[..n].iter().filter(|&&t| t == "a").count(), n * 4 / 5);
+  }
+  // Once "a" runs out, "b" gets the rest.
+  assert_eq!(received.len(), 200);
+  assert!(received[125..].iter().all(|&t| t == "b"));
+}
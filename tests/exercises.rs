@@ -1,9 +1,10 @@
-#![allow(unused)]
-use std::sync::{mpsc, Mutex, Arc, Condvar};
+use std::sync::{mpsc, Mutex, Arc};
 use std::thread;
 use std::time::Duration;
 
-/** In this week's lecture, we have looked at using concurrency in Rust.
+use test_cargo::{new_chan, new_multi_chan};
+
+/* In this week's lecture, we have looked at using concurrency in Rust.
 We have looked at:
 
  - How to spawn threads with `thread::spawn(move ||{ ... })`.
@@ -78,7 +79,7 @@ function, i.e., `fn main() { do stuff }`. To run main, you need to compile
 without `--test`. */
 
 
-/// Part 1: Thread spawning & Join handles
+// Part 1: Thread spawning & Join handles
 
 /* The following program spawns 10 threads and prints a number of messages.
 Modify the program to use join handles to make sure that the main thread does
@@ -97,7 +98,7 @@ fn test_spawn() {
   }
 }
 
-/// Part 2: Message passing
+// Part 2: Message passing
 
 /* Currently, the following program spawns a child thread, which sends the
 vector of `[1,2,3]` to the main thread. The main thread then prints this vector.
@@ -139,16 +140,17 @@ fn test_send_recv() {
 }
 
 // It is safe to mutate the vector because it is sent back and forth between the main
-thread and child using channels
+// thread and child using channels
 
 
 
-/// Part 3: Arc, Mutex & MutexGuard
+// Part 3: Arc, Mutex & MutexGuard
 
 /* Explain what the following program does if you uncomment the last three
 lines. Insert a call to `drop(..)` to make the program print "7".  */
 
 #[test]
+#[allow(clippy::assign_op_pattern)]
 fn test_mutex() {
   let m = Mutex::new(5);
 
@@ -170,6 +172,8 @@ Question: Is this program guaranteed to terminate? If yes, why? If not, why not?
 Will it terminate in practice? */
 
 #[test]
+#[ignore]
+#[allow(unused_variables, clippy::never_loop)]
 fn test_mutex_arc() {
   let counter = Arc::new(Mutex::new(0));
 
@@ -189,138 +193,49 @@ fn test_mutex_arc() {
 }
 
 
-/// Part 4: Condition variables
-
-/* In this exercise, we will implement a one-shot channel. A one-shot channel is
-a channel on which you can send one message. The representation of the channel
-is a `Mutex<Option<T>>`. The option will be `None` if the message has not been
-sent yet, and `Some(msg)` if the message has been sent.
-
-You will first make an implementation of the one-shot channel without condition
-variables. */
-
-// Representation of the one-shot channel in memory
-
-struct Repr<T> {
-  val: Mutex<Option<T>>,
-}
-
-// The capability held by the sender
-
-struct Send<T> {
-  repr: Arc<Repr<T>>
-}
-
-// The capability held by the receiver
-
-struct Recv<T> {
-  repr: Arc<Repr<T>>
-}
-
-// This function creates a new one-shot channel
-
-fn new_chan<T>() -> (Send<T>, Recv<T>) {
-  unimplemented!()
-}
+// Part 4: Condition variables
 
-// The receiver will acquire the mutex, and check if the option is `Some(msg)`
-// If it is, we will return the `msg` in the option.
-// If the option is `None`, we will spin around the loop.
-
-impl<T> Recv<T> {
-  fn recv(self) -> T {
-    loop {
-      let mut x = self.repr.val.lock().unwrap();
-      // We take the option out of the mutex and replace the value in the
-      // mutex with `None`. The `option.take()` function does this for us.
-      let y = x.take();
-      match y {
-        Some(msg) => return msg,
-        None => {
-          // Unlock the mutex and spin around the loop.
-          drop(x)
-        }
-      }
-    }
-  }
-}
-
-// The sender acquires the mutex and stores `Some(msg)` in the mutex.
-
-impl<T> Send<T> {
-  fn send(self, msg: T) -> () {
-    unimplemented!()
-  }
-}
+/* In this exercise, we implemented a one-shot channel, see `src/oneshot.rs`.
+A one-shot channel is a channel on which you can send one message. */
 
 // Here is a function to test the one-shot channel.
 
 #[test]
+#[allow(non_snake_case)]
 fn test_SR() {
   let (s,r) = new_chan();
   let h = thread::spawn(move || {
     println!("Send: 10");
     thread::sleep(Duration::from_millis(1000));
-    s.send(10);
+    s.send(10).unwrap();
     println!("Sent.");
   });
   println!("Receive.");
-  let n = r.recv();
+  let n = r.recv().unwrap();
   println!("Received: {}", n);
+  h.join().unwrap();
 }
 
 /* Exercise:
 
-The `recv()` currently uses a spin loop. This is inefficient, because it wastes
-a thread and will warm up your computer. Furthermore, if the scheduler is not
-fair, then a spin loop implementation is not even correct: the scheduler might
-choose to keep running the spin loop, never giving the sender a chance to send
-the message.
+A first version of `recv()` used a spin loop. This is inefficient, because it
+wastes a thread and will warm up your computer. Furthermore, if the scheduler is
+not fair, then a spin loop implementation is not even correct: the scheduler
+might choose to keep running the spin loop, never giving the sender a chance to
+send the message.
 
-Modify the channel implemtation above to use condition variables
-[`Condvar`](https://doc.rust-lang.org/std/sync/struct.Condvar.html) to make sure
-that the receive is not spinning in a hot loop when waiting to receive. Even if
-the scheduler is not fair, this will be correct, because when we are waiting on
-a condition variable, the scheduler must schedule some other thread. */
+The channel in `src/oneshot.rs` therefore uses a condition variable
+[`Condvar`](https://doc.rust-lang.org/std/sync/struct.Condvar.html), so that the
+receiver is not spinning in a hot loop when waiting to receive. Even if the
+scheduler is not fair, this is correct, because when we are waiting on a
+condition variable, the scheduler must schedule some other thread. */
 
 
 // Part 5: From single-shot to multi-shot
 
 /* In this exercise, we build multi-shot channels from single-shot channels. */
 
-// These are the representations of the receiver and sender that can be used
-// to send multiple messages.
-
-struct MultiRecv<T> {
-  receiver: Recv<(T,MultiRecv<T>)>
-}
-struct MultiSend<T> {
-  sender: Send<(T,MultiRecv<T>)>
-}
-
-// Implement this function in terms of `new_chan()` for single-shot channels.
-
-fn new_multi_chan<T>() -> (MultiSend<T>,MultiRecv<T>) {
-  unimplemented!()
-}
-
-// Implement this function in terms of the API for single-shot channels.
-
-impl<T> MultiRecv<T> {
-  fn recv(self) -> (T,MultiRecv<T>) {
-    unimplemented!()
-  }
-}
-
-// Implemente this function in terms of the API for single-shot channels.
-// Hint: you may need to use the function `new_multi_chan()` that you previously
-// defined.
-
-impl<T> MultiSend<T> {
-  fn send(self, msg: T) -> MultiSend<T> {
-    unimplemented!()
-  }
-}
+// See `src/multishot.rs` for the implementation of `MultiSend`/`MultiRecv`.
 
 #[test]
 fn test_multi_chan() {
@@ -329,15 +244,23 @@ fn test_multi_chan() {
   thread::spawn(move ||{
     for i in 0..10 {
       println!("Send: {}", i);
-      s = s.send(i);
+      s = s.send(i).unwrap();
       println!("Sent.");
     }
+    s.close();
   });
   loop {
     println!("Receive.");
-    let (msg, r2) = r.recv();
-    println!("Received: {}", msg);
-    r = r2;
+    match r.recv() {
+      Some((msg, r2)) => {
+        println!("Received: {}", msg);
+        r = r2;
+      }
+      None => {
+        println!("Closed.");
+        break;
+      }
+    }
   }
 }
 
@@ -361,5 +284,3 @@ In particular:
 
 Hint: modify the `MultiSend`/`MultiRecv` struct definitions to have an
 `Option<...>` somewhere. */
-
-fn main() {}